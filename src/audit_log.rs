@@ -0,0 +1,344 @@
+use std::collections::BTreeSet;
+
+use anyhow::Result;
+use poise::serenity_prelude::{ChannelId, Colour, GuildId, Http, Timestamp};
+
+use crate::config::Config;
+use crate::guild_config::GuildConfig;
+use crate::membership::Membership;
+use crate::prune::PruneOutcome;
+use crate::scraper::MembershipDiff;
+
+/// Discord's length limit for an embed field value.
+const FIELD_LIMIT: usize = 1024;
+
+/// An embed for the audit channel, recording a change to memberships.
+#[derive(Debug, PartialEq)]
+pub struct AuditEntry {
+    pub title: String,
+    pub colour: Colour,
+    /// Name, value and whether the field is inline.
+    pub fields: Vec<(String, String, bool)>,
+}
+
+impl AuditEntry {
+    /// An action taken on `target_id` by `actor_id`, or by Bruce itself if `None`.
+    pub fn action(
+        title: &str,
+        colour: Colour,
+        actor_id: Option<u64>,
+        target_id: u64,
+        student_id: Option<u32>,
+        outcome: impl Into<String>,
+    ) -> Self {
+        Self {
+            title: title.to_string(),
+            colour,
+            fields: vec![
+                ("By".to_string(), actor(actor_id), true),
+                ("Member".to_string(), format!("<@{}>", target_id), true),
+                (
+                    "Student ID".to_string(),
+                    student_id
+                        .map(|id| id.to_string())
+                        .unwrap_or_else(|| "none".to_string()),
+                    true,
+                ),
+                ("Outcome".to_string(), outcome.into(), false),
+            ],
+        }
+    }
+
+    /// A prune by `actor_id`, or an automatic one if `None`.
+    pub fn prune(actor_id: Option<u64>, outcome: &PruneOutcome) -> Self {
+        let mut fields = vec![
+            ("By".to_string(), actor(actor_id), true),
+            (
+                "Outcome".to_string(),
+                format!(
+                    "Removed the member role from {} users and deleted {} memberships",
+                    outcome.pruned,
+                    outcome.deleted.len()
+                ),
+                false,
+            ),
+        ];
+        if !outcome.deleted.is_empty() {
+            fields.push((
+                "Deleted".to_string(),
+                field_list(outcome.deleted.iter().map(describe)),
+                false,
+            ));
+        }
+        if !outcome.failed.is_empty() {
+            fields.push((
+                "Failed".to_string(),
+                field_list(
+                    outcome
+                        .failed
+                        .iter()
+                        .map(|(tag, e)| format!("{}: {}", tag, e)),
+                ),
+                false,
+            ));
+        }
+        Self {
+            title: "Prune".to_string(),
+            colour: Colour::RED,
+            fields,
+        }
+    }
+
+    /// An action by `actor_id`, or by Bruce itself if `None`, that stopped with `error`.
+    pub fn failure(title: &str, actor_id: Option<u64>, error: &anyhow::Error) -> Self {
+        Self {
+            title: format!("{} failed", title),
+            colour: Colour::RED,
+            fields: vec![
+                ("By".to_string(), actor(actor_id), true),
+                ("Error".to_string(), truncate(&error.to_string()), false),
+            ],
+        }
+    }
+
+    /// The joins, lapses and renewals in `diff`, or `None` if there weren't any.
+    pub fn sync(diff: &MembershipDiff) -> Option<Self> {
+        let mut fields = vec![];
+        let lapsed: Vec<&Membership> = diff.dropped.iter().chain(&diff.removed).collect();
+        for (name, memberships) in [
+            ("Joined", diff.joined.iter().collect()),
+            ("Lapsed", lapsed),
+            ("Renewed", diff.renewed.iter().collect::<Vec<_>>()),
+        ] {
+            if !memberships.is_empty() {
+                fields.push((
+                    format!("{} ({})", name, memberships.len()),
+                    field_list(memberships.into_iter().map(describe)),
+                    false,
+                ));
+            }
+        }
+        if fields.is_empty() {
+            return None;
+        }
+        Some(Self {
+            title: "Membership sync".to_string(),
+            colour: Colour::BLURPLE,
+            fields,
+        })
+    }
+}
+
+fn actor(actor_id: Option<u64>) -> String {
+    match actor_id {
+        Some(id) => format!("<@{}>", id),
+        None => "Bruce (automatic)".to_string(),
+    }
+}
+
+fn describe(membership: &Membership) -> String {
+    match membership.discord_id {
+        Some(id) => format!("{} ({}, <@{}>)", membership.name, membership.student_id, id),
+        None => format!("{} ({})", membership.name, membership.student_id),
+    }
+}
+
+/// `value` cut to fit in a field.
+fn truncate(value: &str) -> String {
+    match value.char_indices().nth(FIELD_LIMIT) {
+        Some((end, _)) => value[..end].to_string(),
+        None => value.to_string(),
+    }
+}
+
+/// One line per item, cut short with a count of the rest if they don't fit in a field.
+pub(crate) fn field_list(lines: impl Iterator<Item = String>) -> String {
+    let lines: Vec<String> = lines.collect();
+    let mut value = String::new();
+    for (i, line) in lines.iter().enumerate() {
+        let rest = format!("and {} more", lines.len() - i);
+        if value.len() + line.len() + rest.len() + 2 > FIELD_LIMIT {
+            value.push_str(&rest);
+            return value;
+        }
+        value.push_str(line);
+        value.push('\n');
+    }
+    value.trim_end().to_string()
+}
+
+/// Posts `entry` to the audit channel of `guild_id`, if it has one. Failures are only logged, as
+/// the change has already happened.
+pub async fn log_action(config: &Config, guild_id: GuildId, entry: AuditEntry) {
+    if let Err(e) = post_action(config, guild_id, &entry).await {
+        log::error!("Failed to post {} to the audit channel: {}", entry.title, e);
+    }
+}
+
+async fn post_action(config: &Config, guild_id: GuildId, entry: &AuditEntry) -> Result<()> {
+    let channel_id = match config.audit_channel_id {
+        Some(channel_id) => ChannelId(channel_id),
+        None => return Ok(()),
+    };
+    if !config.channel_in_guild(channel_id, guild_id).await? {
+        return Ok(());
+    }
+    post(&config.get_http(), channel_id, entry).await
+}
+
+/// Posts the changes a sync of `config.scope` made to every audit channel of the guilds using
+/// those memberships.
+pub async fn log_sync(config: &Config, diff: &MembershipDiff) {
+    let entry = match AuditEntry::sync(diff) {
+        Some(entry) => entry,
+        None => return,
+    };
+    let channels = match sync_channels(config) {
+        Ok(channels) => channels,
+        Err(e) => {
+            log::error!("Failed to find the audit channels: {}", e);
+            return;
+        }
+    };
+    let http = config.get_http();
+    for channel_id in channels {
+        if let Err(e) = post(&http, channel_id, &entry).await {
+            log::error!(
+                "Failed to post a sync to audit channel {}: {}",
+                channel_id,
+                e
+            );
+        }
+    }
+}
+
+/// The audit channels set with `/config` by the guilds using `config.scope`, and the
+/// environment's for the shared `MEMBERS_URL` memberships.
+fn sync_channels(config: &Config) -> Result<Vec<ChannelId>> {
+    let mut channels = BTreeSet::new();
+    if config.scope == 0 {
+        channels.extend(config.audit_channel_id);
+    }
+    for guild in GuildConfig::get_all(&config.get_sqlite_conn()?)? {
+        if guild.scope() == config.scope {
+            channels.extend(guild.audit_channel_id);
+        }
+    }
+    Ok(channels.into_iter().map(ChannelId).collect())
+}
+
+async fn post(http: &Http, channel_id: ChannelId, entry: &AuditEntry) -> Result<()> {
+    channel_id
+        .send_message(http, |m| {
+            m.embed(|e| {
+                e.title(&entry.title)
+                    .colour(entry.colour)
+                    .timestamp(Timestamp::now());
+                for (name, value, inline) in &entry.fields {
+                    e.field(name, value, *inline);
+                }
+                e
+            })
+        })
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audit_log::{field_list, sync_channels, AuditEntry, FIELD_LIMIT};
+    use crate::config::Config;
+    use crate::guild_config::GuildConfig;
+    use crate::membership::Membership;
+    use crate::migrations::migrate;
+    use crate::scraper::MembershipDiff;
+    use anyhow::anyhow;
+    use poise::serenity_prelude::ChannelId;
+
+    fn membership(student_id: u32, discord_id: Option<u64>) -> Membership {
+        Membership {
+            student_id,
+            name: format!("Student {}", student_id),
+            discord_id,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn sync_entry() {
+        assert_eq!(AuditEntry::sync(&MembershipDiff::default()), None);
+        let diff = MembershipDiff {
+            joined: vec![membership(1, None), membership(2, None)],
+            dropped: vec![membership(3, Some(30))],
+            removed: vec![membership(4, None)],
+            updated: vec![membership(5, None)],
+            ..Default::default()
+        };
+        let fields: Vec<(String, String)> = AuditEntry::sync(&diff)
+            .unwrap()
+            .fields
+            .into_iter()
+            .map(|(name, value, _)| (name, value))
+            .collect();
+        assert_eq!(
+            fields,
+            vec![
+                (
+                    "Joined (2)".to_string(),
+                    "Student 1 (1)\nStudent 2 (2)".to_string()
+                ),
+                (
+                    "Lapsed (2)".to_string(),
+                    "Student 3 (3, <@30>)\nStudent 4 (4)".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn long_lists_are_cut_short() {
+        let value = field_list((0..500).map(|i| format!("Student {}", i)));
+        assert!(value.len() <= FIELD_LIMIT);
+        assert!(value.starts_with("Student 0\nStudent 1\n"));
+        assert!(value.ends_with(" more"));
+    }
+
+    #[test]
+    fn failure_entry() {
+        let entry = AuditEntry::failure("Prune", Some(7), &anyhow!("{}", "x".repeat(2000)));
+        assert_eq!(entry.title, "Prune failed");
+        assert_eq!(entry.fields[0].1, "<@7>");
+        assert_eq!(entry.fields[1].1.len(), FIELD_LIMIT);
+    }
+
+    #[test]
+    fn sync_channels_by_scope() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config::test(dir.path());
+        config.audit_channel_id = Some(1);
+        let mut conn = config.get_sqlite_conn().unwrap();
+        migrate(&mut conn).unwrap();
+        for guild in [
+            GuildConfig {
+                guild_id: 10,
+                audit_channel_id: Some(2),
+                ..Default::default()
+            },
+            GuildConfig {
+                guild_id: 20,
+                members_url: Some("https://sums.su/groups/400/members".to_string()),
+                audit_channel_id: Some(3),
+                ..Default::default()
+            },
+        ] {
+            guild.save(&conn).unwrap();
+        }
+
+        assert_eq!(
+            sync_channels(&config).unwrap(),
+            vec![ChannelId(1), ChannelId(2)]
+        );
+        let guild = config.for_guild(20).unwrap();
+        assert_eq!(sync_channels(&guild).unwrap(), vec![ChannelId(3)]);
+    }
+}
@@ -1,16 +1,49 @@
 use anyhow::{anyhow, Error, Result};
-use poise::serenity_prelude::{Member, RoleId, UserId};
+use poise::serenity_prelude::{
+    AttachmentType, ButtonStyle, Colour, GuildChannel, InteractionResponseType, Member, Role,
+    RoleId,
+};
 use poise::{serenity_prelude as serenity, FrameworkBuilder, PrefixFrameworkOptions};
+use rusqlite::Connection;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::time::Duration;
 
-use crate::config::Config;
+use crate::audit_log::{self, AuditEntry};
+use crate::config::{parse_members_pages, Config};
+use crate::guild_config::GuildConfig;
+use crate::guild_members;
 use crate::membership::Membership;
+use crate::membership_event::{EventKind, MembershipEvent};
+use crate::membership_source;
+use crate::prune::PrunePlan;
+use crate::scrape_layout::LayoutMismatch;
+use crate::scraper;
+use crate::stats::MembershipStats;
+use crate::type_roles::RoleMap;
 
 type Context<'a> = poise::Context<'a, Config, Error>;
 
+/// Discord's message length limit, less some room for a summary line.
+const MESSAGE_LIMIT: usize = 1900;
+const PRUNE_CONFIRM_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
 pub fn build_framework(config: Config) -> FrameworkBuilder<Config, Error> {
     poise::Framework::build()
         .options(poise::FrameworkOptions {
-            commands: vec![setup_commands(), register(), unregister(), prune()],
+            commands: vec![
+                setup_commands(),
+                register(),
+                unregister(),
+                prune(),
+                history(),
+                whois(),
+                lookup(),
+                stats(),
+                set_cookie(),
+                setup(),
+                configure(),
+            ],
             prefix_options: PrefixFrameworkOptions {
                 prefix: Some("bruce!".to_string()),
                 ..Default::default()
@@ -41,14 +74,16 @@ async fn register(
     #[description = "Discord member to perform registration on, or if empty, yourself"]
     target_member: Option<Member>,
 ) -> Result<()> {
-    let data = ctx.data();
+    let data = &guild_config(ctx)?;
     let author_member = ctx
         .author_member()
         .await
         .ok_or_else(|| anyhow!("Failed to retrieve calling user"))?;
     let mut target_member = if let Some(target_member) = target_member {
         if author_member.user.id != target_member.user.id
-            && !author_member.roles.contains(&get_privileged_role(ctx)?)
+            && !author_member
+                .roles
+                .contains(&get_privileged_role(ctx, data)?)
         {
             ctx.say("You don't have the required permissions to target a user")
                 .await?;
@@ -71,7 +106,14 @@ async fn register(
 
     let conn = data.get_sqlite_conn()?;
 
-    if Membership::get_by_discord_id(&conn, *target_member.user.id.as_u64()).is_ok() {
+    if Membership::get_by_discord_id(
+        &conn,
+        data.scope,
+        data.guild_id,
+        *target_member.user.id.as_u64(),
+    )
+    .is_ok()
+    {
         ctx.say(format!(
             "Target user ({}) is already registered, use /unregister to remove them or @ a committee member",
             target_member.display_name()
@@ -80,7 +122,7 @@ async fn register(
         return Ok(());
     }
 
-    let membership = Membership::get_by_student_id(&conn, student_id);
+    let membership = Membership::get_by_student_id(&conn, data.scope, data.guild_id, student_id);
 
     if membership.is_err() {
         let mut membership_link = "".to_string();
@@ -94,23 +136,71 @@ async fn register(
     let mut membership = membership.unwrap();
 
     if let Some(id) = membership.discord_id {
-        if *target_member.user.id.as_u64() != id as u64 {
+        if *target_member.user.id.as_u64() != id {
             ctx.say("Somebody else has already registered with that student id :eyes:\nIf you think this is a mistake, please @ someone on Committee.").await?;
             return Ok(());
         }
     }
 
-    membership.update_disord_id(&conn, Some(*target_member.user.id.as_u64()))?;
+    // Find the roles before linking anything, so a missing role can't leave a member linked
+    // without them
+    let mut roles = vec![get_member_role(ctx, data)?];
+    roles.extend(get_role_map(ctx, data)?.roles_for_membership(&membership));
 
-    target_member
-        .add_role(ctx.data().get_http(), get_member_role(ctx)?)
-        .await?;
+    membership.link(&conn, data.guild_id, *target_member.user.id.as_u64())?;
+    MembershipEvent::record(
+        &conn,
+        membership.guild_id,
+        membership.student_id,
+        membership.discord_id,
+        EventKind::Registered,
+        Some(*author_member.user.id.as_u64()),
+    )?;
+
+    if let Err(e) = target_member.add_roles(ctx.data().get_http(), &roles).await {
+        audit_log::log_action(
+            data,
+            target_member.guild_id,
+            AuditEntry::action(
+                "Registration",
+                Colour::RED,
+                Some(*author_member.user.id.as_u64()),
+                *target_member.user.id.as_u64(),
+                Some(membership.student_id),
+                format!(
+                    "Linked to {}, but their roles couldn't be added: {}",
+                    membership.name, e
+                ),
+            ),
+        )
+        .await;
+        return Err(e.into());
+    }
 
     let result = target_member
         .edit(ctx.data().get_http(), |edit| {
             edit.nickname(&membership.name)
         })
         .await;
+    audit_log::log_action(
+        data,
+        target_member.guild_id,
+        AuditEntry::action(
+            "Registration",
+            Colour::DARK_GREEN,
+            Some(*author_member.user.id.as_u64()),
+            *target_member.user.id.as_u64(),
+            Some(membership.student_id),
+            match result {
+                Ok(_) => format!("Registered as {}", membership.name),
+                Err(_) => format!(
+                    "Registered as {}, but their nickname couldn't be changed",
+                    membership.name
+                ),
+            },
+        ),
+    )
+    .await;
     if result.is_err() {
         ctx.say(format!(
             "Done! Please change your nickname to: {}",
@@ -136,49 +226,110 @@ async fn unregister(
         .author_member()
         .await
         .ok_or_else(|| anyhow!("Failed to retrieve calling user"))?;
-    if !author_member.roles.contains(&get_privileged_role(ctx)?) {
+    let config = guild_config(ctx)?;
+    if !author_member
+        .roles
+        .contains(&get_privileged_role(ctx, &config)?)
+    {
         ctx.say("Only privileged users can run this command")
             .await?;
         return Ok(());
     }
-    let conn = ctx.data().get_sqlite_conn()?;
-    target_member
-        .remove_role(ctx.data().get_http(), get_member_role(ctx)?)
-        .await?;
-    if let Ok(mut m) = Membership::get_by_discord_id(&conn, *target_member.user.id.as_u64()) {
-        m.update_disord_id(&conn, None)?;
+    let conn = config.get_sqlite_conn()?;
+    let mut roles = vec![get_member_role(ctx, &config)?];
+    roles.extend(
+        get_role_map(ctx, &config)?
+            .all()
+            .into_iter()
+            .filter(|r| target_member.roles.contains(r)),
+    );
+    if let Err(e) = target_member
+        .remove_roles(ctx.data().get_http(), &roles)
+        .await
+    {
+        audit_log::log_action(
+            &config,
+            target_member.guild_id,
+            AuditEntry::action(
+                "Unregistration",
+                Colour::RED,
+                Some(*author_member.user.id.as_u64()),
+                *target_member.user.id.as_u64(),
+                None,
+                format!(
+                    "Nothing was changed, as their roles couldn't be removed: {}",
+                    e
+                ),
+            ),
+        )
+        .await;
+        return Err(e.into());
     }
+    let mut membership = Membership::get_by_discord_id(
+        &conn,
+        config.scope,
+        config.guild_id,
+        *target_member.user.id.as_u64(),
+    )
+    .ok();
+    if let Some(m) = &mut membership {
+        m.unlink(&conn, config.guild_id)?;
+        MembershipEvent::record(
+            &conn,
+            m.guild_id,
+            m.student_id,
+            Some(*target_member.user.id.as_u64()),
+            EventKind::Unregistered,
+            Some(*author_member.user.id.as_u64()),
+        )?;
+    }
+    audit_log::log_action(
+        &config,
+        target_member.guild_id,
+        AuditEntry::action(
+            "Unregistration",
+            Colour::ORANGE,
+            Some(*author_member.user.id.as_u64()),
+            *target_member.user.id.as_u64(),
+            membership.as_ref().map(|m| m.student_id),
+            match &membership {
+                Some(m) => format!("Unlinked from {} and removed the member role", m.name),
+                None => "Removed the member role, no student ID was linked".to_string(),
+            },
+        ),
+    )
+    .await;
     ctx.say("User unregistered").await?;
     Ok(())
 }
 
 #[poise::command(slash_command, guild_only)]
-async fn prune(ctx: Context<'_>) -> Result<(), Error> {
+async fn prune(
+    ctx: Context<'_>,
+    #[description = "Preview who would be pruned before confirming"] dry_run: Option<bool>,
+) -> Result<(), Error> {
     let author_member = ctx
         .author_member()
         .await
         .ok_or_else(|| anyhow!("Failed to retrieve calling user"))?;
     log::info!("Prune called by {}", author_member.display_name());
-    if !author_member.roles.contains(&get_privileged_role(ctx)?) {
+    let config = guild_config(ctx)?;
+    if !author_member
+        .roles
+        .contains(&get_privileged_role(ctx, &config)?)
+    {
         ctx.say("Only privileged users can run this command")
             .await?;
         return Ok(());
     }
 
-    let conn = ctx.data().get_sqlite_conn()?;
-    let memberships: Vec<Membership> = Membership::get_all(&conn)?
-        .into_iter()
-        .filter(|m| m.discord_id.is_some())
-        .collect();
-    let users = ctx
+    let memberships =
+        Membership::get_all(&config.get_sqlite_conn()?, config.scope, config.guild_id)?;
+    let guild = ctx
         .guild()
-        .ok_or_else(|| anyhow!("Failed to retrieve server information"))?
-        .members(ctx.data().get_http(), None, None)
-        .await?;
-    let user_count = ctx
-        .guild()
-        .ok_or_else(|| anyhow!("Failed to retrieve server information"))?
-        .member_count as usize;
+        .ok_or_else(|| anyhow!("Failed to retrieve server information"))?;
+    let users = guild_members::fetch_all(&ctx.data().get_http(), guild.id).await?;
+    let user_count = guild.member_count as usize;
 
     if users.len() != user_count {
         return Err(anyhow!(
@@ -190,42 +341,668 @@ async fn prune(ctx: Context<'_>) -> Result<(), Error> {
 
     ctx.say(format!("Checking {} users", users.len())).await?;
 
-    let mut count = 0;
-    for mut member in users {
-        let membership = memberships
-            .iter()
-            .find(|m| m.discord_id.unwrap() == *member.user.id.as_u64());
-        if membership.is_none() || membership.unwrap().should_drop {
-            member
-                .remove_role(ctx.data().get_http(), get_member_role(ctx)?)
+    let plan = PrunePlan::new(
+        users,
+        memberships,
+        get_member_role(ctx, &config)?,
+        get_role_map(ctx, &config)?.all(),
+        None,
+    );
+    if dry_run.unwrap_or(false) && !confirm_prune(ctx, &plan).await? {
+        return Ok(());
+    }
+
+    let actor_id = Some(*author_member.user.id.as_u64());
+    let outcome = match plan
+        .execute(&ctx.data().get_http(), &config, actor_id)
+        .await
+    {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            audit_log::log_action(
+                &config,
+                guild.id,
+                AuditEntry::failure("Prune", actor_id, &e),
+            )
+            .await;
+            return Err(e);
+        }
+    };
+    audit_log::log_action(&config, guild.id, AuditEntry::prune(actor_id, &outcome)).await;
+
+    ctx.say(format!(
+        "Pruned {} users{}",
+        outcome.pruned,
+        outcome.failure_report()
+    ))
+    .await?;
+
+    Ok(())
+}
+
+/// Shows what `plan` would do with a button to go ahead, returning whether it was pressed.
+async fn confirm_prune(ctx: Context<'_>, plan: &PrunePlan) -> Result<bool, Error> {
+    if plan.is_empty() {
+        ctx.say("Nobody needs pruning :tada:").await?;
+        return Ok(false);
+    }
+
+    let summary = format!(
+        "{} users would lose the member role and {} lapsed memberships would be deleted",
+        plan.targets.len(),
+        plan.memberships.len()
+    );
+    let report = plan.report();
+    let confirm_id = format!("prune-confirm-{}", ctx.id());
+    let reply = ctx
+        .send(|m| {
+            if summary.len() + report.len() < MESSAGE_LIMIT {
+                m.content(format!("{}\n{}", summary, report));
+            } else {
+                m.content(&summary).attachment(AttachmentType::Bytes {
+                    data: Cow::Owned(report.clone().into_bytes()),
+                    filename: "prune.txt".to_string(),
+                });
+            }
+            m.components(|c| {
+                c.create_action_row(|r| {
+                    r.create_button(|b| {
+                        b.custom_id(&confirm_id)
+                            .label("Prune")
+                            .style(ButtonStyle::Danger)
+                    })
+                })
+            })
+        })
+        .await?;
+
+    let interaction = reply
+        .message()
+        .await?
+        .await_component_interaction(ctx.discord())
+        .author_id(ctx.author().id)
+        .filter(move |i| i.data.custom_id == confirm_id)
+        .timeout(PRUNE_CONFIRM_TIMEOUT)
+        .await;
+    match interaction {
+        Some(interaction) => {
+            interaction
+                .create_interaction_response(ctx.discord(), |r| {
+                    r.kind(InteractionResponseType::UpdateMessage)
+                        .interaction_response_data(|d| d.components(|c| c))
+                })
                 .await?;
-            count += 1;
-            log::info!("Removing roles from {}", member.user.name);
+            Ok(true)
+        }
+        None => {
+            ctx.say("Prune cancelled, nobody confirmed it in time")
+                .await?;
+            Ok(false)
         }
     }
+}
 
-    ctx.say(format!("Pruned {count} users")).await?;
+const HISTORY_LIMIT: usize = 20;
 
-    for membership in memberships.into_iter().filter(|m| m.should_drop) {
-        membership.delete(&conn)?;
+#[poise::command(slash_command, guild_only)]
+async fn history(
+    ctx: Context<'_>,
+    #[description = "Student ID to show the history of"] student_id: Option<u32>,
+    #[description = "Discord member to show the history of"] target_member: Option<Member>,
+) -> Result<(), Error> {
+    let author_member = ctx
+        .author_member()
+        .await
+        .ok_or_else(|| anyhow!("Failed to retrieve calling user"))?;
+    let config = guild_config(ctx)?;
+    if !author_member
+        .roles
+        .contains(&get_privileged_role(ctx, &config)?)
+    {
+        ctx.say("Only privileged users can run this command")
+            .await?;
+        return Ok(());
     }
 
+    let conn = config.get_sqlite_conn()?;
+    let scope = config.scope;
+    let events = match (student_id, target_member) {
+        (Some(student_id), _) => MembershipEvent::get_by_student_id(&conn, scope, student_id)?,
+        (None, Some(member)) => {
+            let discord_id = *member.user.id.as_u64();
+            match Membership::get_by_discord_id(&conn, scope, config.guild_id, discord_id) {
+                Ok(membership) => {
+                    MembershipEvent::get_by_student_id(&conn, scope, membership.student_id)?
+                }
+                Err(_) => MembershipEvent::get_by_discord_id(&conn, scope, discord_id)?,
+            }
+        }
+        (None, None) => {
+            ctx.say("Give me a student id or a member to look up")
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let content = if events.is_empty() {
+        "I don't have any history for that :shrug:".to_string()
+    } else {
+        let mut lines: Vec<String> = events
+            .iter()
+            .skip(events.len().saturating_sub(HISTORY_LIMIT))
+            .map(|e| {
+                let mut line = format!("<t:{}:f> `{}` {}", e.created_at, e.student_id, e.kind);
+                if let Some(discord_id) = e.discord_id {
+                    line.push_str(&format!(" <@{}>", discord_id));
+                }
+                if let Some(actor_id) = e.actor_id {
+                    line.push_str(&format!(" by <@{}>", actor_id));
+                }
+                line
+            })
+            .collect();
+        if events.len() > HISTORY_LIMIT {
+            lines.insert(
+                0,
+                format!(
+                    "Showing the latest {} of {} events",
+                    HISTORY_LIMIT,
+                    events.len()
+                ),
+            );
+        }
+        lines.join("\n")
+    };
+    ctx.send(|m| m.content(content).ephemeral(true)).await?;
     Ok(())
 }
 
-fn get_member_role(ctx: Context<'_>) -> Result<RoleId, Error> {
-    get_role_id(ctx, ctx.data().member_role_name.as_str())
+#[poise::command(slash_command, guild_only, ephemeral)]
+async fn whois(
+    ctx: Context<'_>,
+    #[description = "The discord member to look up"] target_member: Member,
+) -> Result<(), Error> {
+    let author_member = ctx
+        .author_member()
+        .await
+        .ok_or_else(|| anyhow!("Failed to retrieve calling user"))?;
+    let config = guild_config(ctx)?;
+    if !author_member
+        .roles
+        .contains(&get_privileged_role(ctx, &config)?)
+    {
+        ctx.say("Only privileged users can run this command")
+            .await?;
+        return Ok(());
+    }
+
+    let conn = config.get_sqlite_conn()?;
+    let content = match Membership::get_by_discord_id(
+        &conn,
+        config.scope,
+        config.guild_id,
+        *target_member.user.id.as_u64(),
+    ) {
+        Ok(membership) => describe_membership(&conn, &membership)?,
+        Err(_) => format!("<@{}> isn't registered", target_member.user.id),
+    };
+    ctx.say(content).await?;
+    Ok(())
+}
+
+const LOOKUP_LIMIT: usize = 10;
+
+#[poise::command(slash_command, guild_only, ephemeral)]
+async fn lookup(
+    ctx: Context<'_>,
+    #[description = "Student ID to look up"] student_id: Option<u32>,
+    #[description = "Part of a name to search for"] name: Option<String>,
+) -> Result<(), Error> {
+    let author_member = ctx
+        .author_member()
+        .await
+        .ok_or_else(|| anyhow!("Failed to retrieve calling user"))?;
+    let config = guild_config(ctx)?;
+    if !author_member
+        .roles
+        .contains(&get_privileged_role(ctx, &config)?)
+    {
+        ctx.say("Only privileged users can run this command")
+            .await?;
+        return Ok(());
+    }
+
+    let conn = config.get_sqlite_conn()?;
+    // Servers sharing another's memberships only see the members registered with them
+    let linked_only = !config.sees_all_memberships();
+    let content = match (student_id, name) {
+        (Some(student_id), _) => {
+            match Membership::get_by_student_id(&conn, config.scope, config.guild_id, student_id) {
+                Ok(membership) if !linked_only || membership.discord_id.is_some() => {
+                    describe_membership(&conn, &membership)?
+                }
+                _ if linked_only => format!("`{}` isn't registered in this server", student_id),
+                _ => format!("`{}` isn't a current member", student_id),
+            }
+        }
+        (None, Some(name)) => {
+            let mut found = Membership::search_by_name(
+                &conn,
+                config.scope,
+                config.guild_id,
+                &name,
+                LOOKUP_LIMIT + 1,
+                linked_only,
+            )?;
+            match found.len() {
+                0 if linked_only => "Nobody by that name is registered in this server".to_string(),
+                0 => "Nobody by that name is a current member".to_string(),
+                1 => describe_membership(&conn, &found[0])?,
+                n => {
+                    found.truncate(LOOKUP_LIMIT);
+                    let mut lines: Vec<String> = found
+                        .iter()
+                        .map(|m| {
+                            let linked = m
+                                .discord_id
+                                .map(|id| format!(" <@{}>", id))
+                                .unwrap_or_default();
+                            let lapsed = if m.should_drop { " (lapsed)" } else { "" };
+                            format!("`{}` {}{}{}", m.student_id, m.name, lapsed, linked)
+                        })
+                        .collect();
+                    if n > LOOKUP_LIMIT {
+                        lines.push(format!(
+                            "Showing the first {}, try a longer name",
+                            LOOKUP_LIMIT
+                        ));
+                    }
+                    lines.join("\n")
+                }
+            }
+        }
+        (None, None) => "Give me a student id or a name to look up".to_string(),
+    };
+    ctx.say(content).await?;
+    Ok(())
+}
+
+const SOURCE_ERROR_LIMIT: usize = 200;
+
+#[poise::command(slash_command, guild_only, ephemeral)]
+async fn stats(ctx: Context<'_>) -> Result<(), Error> {
+    let author_member = ctx
+        .author_member()
+        .await
+        .ok_or_else(|| anyhow!("Failed to retrieve calling user"))?;
+    let config = guild_config(ctx)?;
+    if !author_member
+        .roles
+        .contains(&get_privileged_role(ctx, &config)?)
+    {
+        ctx.say("Only privileged users can run this command")
+            .await?;
+        return Ok(());
+    }
+
+    let stats = MembershipStats::get(&config)?;
+    let healthy = stats.sources.iter().all(|health| health.healthy);
+    let sources: Vec<String> = stats
+        .sources
+        .iter()
+        .map(|health| match health.unhealthy_since {
+            Some(since) if !health.healthy => {
+                let mut line = format!(":x: {}\nFailing since <t:{}:R>", health.source, since);
+                if let Some(error) = &health.last_error {
+                    // Keep the embed field within Discord's limit
+                    line.push_str(": ");
+                    line.extend(error.chars().take(SOURCE_ERROR_LIMIT));
+                }
+                line
+            }
+            _ => format!(":white_check_mark: {}", health.source),
+        })
+        .collect();
+    ctx.send(|m| {
+        m.embed(|e| {
+            e.title("Membership stats")
+                .colour(if healthy {
+                    Colour::DARK_GREEN
+                } else {
+                    Colour::RED
+                })
+                .field("Members", stats.total, true)
+                .field("Registered", stats.linked, true)
+                .field("Waiting to be pruned", stats.should_drop, true)
+                .field("Registrations this week", stats.registered_last_week, true)
+                .field(
+                    "Registrations this month",
+                    stats.registered_last_month,
+                    true,
+                )
+                .field(
+                    "Last successful scrape",
+                    stats
+                        .last_scrape
+                        .map(|at| format!("<t:{}:R>", at))
+                        .unwrap_or_else(|| "never".to_string()),
+                    true,
+                )
+                .field(
+                    "Members pages",
+                    audit_log::field_list(sources.into_iter()),
+                    false,
+                )
+        })
+        .ephemeral(true)
+    })
+    .await?;
+    Ok(())
+}
+
+/// What committee needs to know about `membership`: who it is, whether it's current and who it's
+/// linked to.
+fn describe_membership(conn: &Connection, membership: &Membership) -> Result<String> {
+    let status = match (membership.should_drop, membership.dropped_at) {
+        (false, _) => "Current member".to_string(),
+        (true, Some(dropped_at)) => format!("Lapsed <t:{}:R>, waiting to be pruned", dropped_at),
+        (true, None) => "Lapsed, waiting to be pruned".to_string(),
+    };
+    let mut lines = vec![
+        format!("Student ID: `{}`", membership.student_id),
+        format!("Name: {}", membership.name),
+        format!("Status: {}", status),
+    ];
+    if let Some(membership_type) = &membership.membership_type {
+        lines.push(format!("Membership: {}", membership_type));
+    }
+    if let Some(expires_at) = membership.expires_at {
+        lines.push(format!("Expires: <t:{}:D>", expires_at));
+    }
+    match membership.discord_id {
+        Some(discord_id) => {
+            lines.push(format!("Discord: <@{}>", discord_id));
+            let registered = MembershipEvent::get_by_student_id(
+                conn,
+                membership.guild_id,
+                membership.student_id,
+            )?
+            .into_iter()
+            .rfind(|e| e.kind == EventKind::Registered && e.discord_id == Some(discord_id));
+            lines.push(match registered {
+                Some(event) => format!("Registered: <t:{}:f>", event.created_at),
+                None => "Registered: before history was kept".to_string(),
+            });
+        }
+        None => lines.push("Discord: not registered".to_string()),
+    }
+    Ok(lines.join("\n"))
+}
+
+#[poise::command(slash_command, guild_only, ephemeral)]
+async fn set_cookie(
+    ctx: Context<'_>,
+    #[description = "The new su_session cookie value from the student dashboard"] value: String,
+) -> Result<(), Error> {
+    let author_member = ctx
+        .author_member()
+        .await
+        .ok_or_else(|| anyhow!("Failed to retrieve calling user"))?;
+    // Every server is scraped with the one session, so only the home server's committee may
+    // replace it
+    let config = guild_config(ctx)?;
+    if config.home_guild_id != Some(config.guild_id) {
+        ctx.say("The SUMS session can only be changed in the server of whoever runs Bruce")
+            .await?;
+        return Ok(());
+    }
+    if !author_member
+        .roles
+        .contains(&get_privileged_role(ctx, &config)?)
+    {
+        ctx.say("Only privileged users can run this command")
+            .await?;
+        return Ok(());
+    }
+    log::info!("Set cookie called by {}", author_member.display_name());
+
+    ctx.defer_ephemeral().await?;
+    // The session is tested against the MEMBERS_URL pages, even if this server has its own
+    let content = match scraper::set_session(ctx.data(), value.trim()).await {
+        Ok((count, Ok(()))) => format!(
+            "Cookie saved, SUMS listed {} memberships with it :cookie:",
+            count
+        ),
+        Ok((count, Err(e))) => format!(
+            "Cookie saved, SUMS listed {} memberships with it, but updating the memberships failed, I'll keep trying: {:#}",
+            count, e
+        ),
+        Err(e) => format!("That cookie didn't work, so I've kept the old one: {:#}", e),
+    };
+    ctx.say(content).await?;
+    Ok(())
+}
+
+#[poise::command(
+    slash_command,
+    guild_only,
+    ephemeral,
+    required_permissions = "MANAGE_GUILD"
+)]
+async fn setup(
+    ctx: Context<'_>,
+    #[description = "The role registered members get"] member_role: Role,
+    #[description = "The role allowed to run committee commands"] privileged_role: Role,
+) -> Result<(), Error> {
+    let guild_id = ctx
+        .guild_id()
+        .ok_or_else(|| anyhow!("Failed to retrieve server information"))?;
+    for role in [&member_role, &privileged_role] {
+        if role.id.as_u64() == guild_id.as_u64() || role.managed {
+            ctx.say(format!(
+                "{} is managed by Discord, pick a role I can hand out",
+                role.name
+            ))
+            .await?;
+            return Ok(());
+        }
+    }
+
+    let conn = ctx.data().get_sqlite_conn()?;
+    let mut guild = GuildConfig::get(&conn, *guild_id.as_u64())?;
+    guild.member_role_id = Some(*member_role.id.as_u64());
+    guild.privileged_role_id = Some(*privileged_role.id.as_u64());
+    guild.save(&conn)?;
+    log::info!(
+        "Roles of {} set to {} and {} by {}",
+        guild_id,
+        member_role.name,
+        privileged_role.name,
+        ctx.author().name
+    );
+    ctx.say(format!(
+        "Members will get <@&{}>, and <@&{}> can run committee commands. Renaming them won't break anything :tada:",
+        member_role.id, privileged_role.id
+    ))
+    .await?;
+    Ok(())
+}
+
+/// A setting `/config` can put back to the default from the environment.
+#[derive(poise::ChoiceParameter)]
+enum ConfigSetting {
+    #[name = "member_role"]
+    MemberRole,
+    #[name = "privileged_role"]
+    PrivilegedRole,
+    #[name = "members_url"]
+    MembersUrl,
+    #[name = "purchase_url"]
+    PurchaseUrl,
+    #[name = "committee_channel"]
+    CommitteeChannel,
+    #[name = "audit_channel"]
+    AuditChannel,
+}
+
+#[poise::command(
+    slash_command,
+    guild_only,
+    ephemeral,
+    rename = "config",
+    required_permissions = "MANAGE_GUILD"
+)]
+async fn configure(
+    ctx: Context<'_>,
+    #[description = "This server's SUMS members pages, written like MEMBERS_URL"]
+    members_url: Option<String>,
+    #[description = "Where to buy a membership, shown when a student ID isn't found"]
+    purchase_url: Option<String>,
+    #[description = "Channel for committee alerts and prune summaries"] committee_channel: Option<
+        GuildChannel,
+    >,
+    #[description = "Channel where membership changes are logged"] audit_channel: Option<
+        GuildChannel,
+    >,
+    #[description = "Setting to put back to the default"] reset: Option<ConfigSetting>,
+) -> Result<(), Error> {
+    let guild_id = ctx
+        .guild_id()
+        .ok_or_else(|| anyhow!("Failed to retrieve server information"))?;
+    if let Some(Err(e)) = members_url
+        .as_deref()
+        .map(|url| ctx.data().check_members_pages(&parse_members_pages(url)?))
+    {
+        ctx.say(format!("Those members pages don't look right: {}", e))
+            .await?;
+        return Ok(());
+    }
+    let pages_changed = members_url.is_some() || matches!(reset, Some(ConfigSetting::MembersUrl));
+    let changed = pages_changed
+        || purchase_url.is_some()
+        || committee_channel.is_some()
+        || audit_channel.is_some()
+        || reset.is_some();
+
+    let guild = {
+        let conn = ctx.data().get_sqlite_conn()?;
+        let mut guild = GuildConfig::get(&conn, *guild_id.as_u64())?;
+        guild.members_url = members_url.or(guild.members_url);
+        guild.membership_purchase_url = purchase_url.or(guild.membership_purchase_url);
+        guild.committee_channel_id = committee_channel
+            .map(|channel| *channel.id.as_u64())
+            .or(guild.committee_channel_id);
+        guild.audit_channel_id = audit_channel
+            .map(|channel| *channel.id.as_u64())
+            .or(guild.audit_channel_id);
+        match reset {
+            Some(ConfigSetting::MemberRole) => {
+                guild.member_role_id = None;
+                guild.member_role_name = None;
+            }
+            Some(ConfigSetting::PrivilegedRole) => {
+                guild.privileged_role_id = None;
+                guild.privileged_role_name = None;
+            }
+            Some(ConfigSetting::MembersUrl) => guild.members_url = None,
+            Some(ConfigSetting::PurchaseUrl) => guild.membership_purchase_url = None,
+            Some(ConfigSetting::CommitteeChannel) => guild.committee_channel_id = None,
+            Some(ConfigSetting::AuditChannel) => guild.audit_channel_id = None,
+            None => {}
+        }
+        if changed {
+            guild.save(&conn)?;
+            log::info!("Config of {} changed by {}", guild_id, ctx.author().name);
+        }
+        guild
+    };
+    let config = guild.apply(ctx.data())?;
+
+    let mut content = describe_config(&guild, &config);
+    if pages_changed {
+        ctx.defer_ephemeral().await?;
+        let result = match membership_source::sources(&config) {
+            Ok(sources) => scraper::sync(&config, &sources).await,
+            Err(e) => Err(e),
+        };
+        content.push_str(&match result {
+            Ok(()) => format!(
+                "\nSynced {} memberships from the members pages :tada:",
+                Membership::get_all(&config.get_sqlite_conn()?, config.scope, config.guild_id)?
+                    .len()
+            ),
+            // Don't echo what was found on the page, the logs have it
+            Err(e) if e.downcast_ref::<LayoutMismatch>().is_some() => {
+                log::error!("Members pages of {} don't match: {:#}", guild_id, e);
+                "\nThe members pages don't match the scrape layout, ask whoever runs Bruce to check them. I'll keep trying".to_string()
+            }
+            Err(e) => format!(
+                "\nCouldn't scrape the members pages yet, I'll keep trying: {:#}",
+                e
+            ),
+        });
+    }
+    ctx.say(content).await?;
+    Ok(())
+}
+
+/// Lists the settings `config` ended up with, marking the ones `guild` changed.
+fn describe_config(guild: &GuildConfig, config: &Config) -> String {
+    let mark = |set: bool| if set { " (set with /config)" } else { "" };
+    let role = |id: Option<u64>, name: &str| match id {
+        Some(id) => format!("<@&{}>", id),
+        None => format!("{} (by name, pick it with /setup)", name),
+    };
+    let channel = |id: Option<u64>| {
+        id.map(|id| format!("<#{}>", id))
+            .unwrap_or_else(|| "none".to_string())
+    };
+    let pages: Vec<String> = config
+        .members_pages
+        .iter()
+        .map(|page| match &page.tag {
+            Some(tag) => format!("{} (tagged `{}`)", page.url, tag),
+            None => page.url.to_string(),
+        })
+        .collect();
+    format!(
+        "Member role: {}\nPrivileged role: {}\nMembers pages: {}{}\nPurchase URL: {}{}\nCommittee channel: {}{}\nAudit channel: {}{}",
+        role(config.member_role_id, &config.member_role_name),
+        role(config.privileged_role_id, &config.privileged_role_name),
+        pages.join(", "),
+        mark(guild.members_url.is_some()),
+        config.membership_purchase_url.as_deref().unwrap_or("none"),
+        mark(guild.membership_purchase_url.is_some()),
+        channel(config.committee_channel_id),
+        mark(guild.committee_channel_id.is_some()),
+        channel(config.audit_channel_id),
+        mark(guild.audit_channel_id.is_some()),
+    )
+}
+
+/// The config of the server the command was run in.
+fn guild_config(ctx: Context<'_>) -> Result<Config, Error> {
+    let guild_id = ctx
+        .guild_id()
+        .ok_or_else(|| anyhow!("Failed to retrieve server information"))?;
+    ctx.data().for_guild(*guild_id.as_u64())
+}
+
+fn get_member_role(ctx: Context<'_>, config: &Config) -> Result<RoleId, Error> {
+    config.member_role(&get_guild_roles(ctx)?)
+}
+
+fn get_privileged_role(ctx: Context<'_>, config: &Config) -> Result<RoleId, Error> {
+    config.privileged_role(&get_guild_roles(ctx)?)
 }
 
-fn get_privileged_role(ctx: Context<'_>) -> Result<RoleId, Error> {
-    get_role_id(ctx, ctx.data().privileged_role_name.as_str())
+fn get_role_map(ctx: Context<'_>, config: &Config) -> Result<RoleMap, Error> {
+    Ok(config.type_role_map(&get_guild_roles(ctx)?))
 }
 
-fn get_role_id(ctx: Context<'_>, role_name: &str) -> Result<RoleId, Error> {
+fn get_guild_roles(ctx: Context<'_>) -> Result<HashMap<RoleId, Role>, Error> {
     Ok(ctx
         .guild()
         .ok_or_else(|| anyhow!("Failed to retrieve server information"))?
-        .role_by_name(role_name)
-        .ok_or_else(|| anyhow!("Role {} could not be found", role_name))?
-        .id)
+        .roles)
 }
@@ -0,0 +1,15 @@
+use anyhow::{anyhow, Result};
+use poise::serenity_prelude::ChannelId;
+
+use crate::config::Config;
+
+/// Posts `content` to the committee channel.
+pub async fn notify(config: &Config, content: impl std::fmt::Display) -> Result<()> {
+    let channel_id = config
+        .committee_channel_id
+        .ok_or_else(|| anyhow!("COMMITTEE_CHANNEL_ID is not set"))?;
+    ChannelId(channel_id)
+        .say(&config.get_http(), content)
+        .await?;
+    Ok(())
+}
@@ -1,43 +1,348 @@
-use anyhow::Result;
-use poise::serenity_prelude::Http;
+use crate::cookie_cipher::CookieCipher;
+use crate::guild_config::GuildConfig;
+use crate::membership_source::SourceKind;
+use crate::prune::PruneSchedule;
+use crate::scrape_layout::{parse_columns, ScrapeLayout};
+use crate::type_roles::{self, role_by_name, RoleMap, TypeRoles};
+use anyhow::{anyhow, Error, Result};
+use poise::serenity_prelude::{ChannelId, GuildId, Http, Role, RoleId};
 use reqwest::Url;
 use rusqlite::Connection;
+use std::collections::HashMap;
 use std::fs::OpenOptions;
 use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Student dashboard credentials, used to log in again whenever the session expires.
+#[derive(Clone)]
+pub struct SumsLogin {
+    pub url: Url,
+    pub username: String,
+    pub password: String,
+}
+
+/// A SUMS group members page, optionally tagged so the memberships found there can be told apart.
+///
+/// Parsed from `<url>` or `<tag>=<url>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MembersPage {
+    pub url: Url,
+    pub tag: Option<String>,
+}
+
+impl FromStr for MembersPage {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (tag, url) = match s.split_once('=') {
+            Some((tag, url)) if !tag.contains([':', '/']) => (Some(tag.trim()), url),
+            _ => (None, s),
+        };
+        if tag.is_some_and(|tag| tag.is_empty() || tag.contains(',')) {
+            return Err(anyhow!("Invalid tag in {}", s));
+        }
+        Ok(Self {
+            url: url
+                .trim()
+                .parse()
+                .map_err(|e| anyhow!("Invalid URL in {}: {}", s, e))?,
+            tag: tag.map(str::to_string),
+        })
+    }
+}
+
+/// Finds a role by `id`, or by `name` if it hasn't been picked with `/setup` yet.
+fn find_role(roles: &HashMap<RoleId, Role>, id: Option<u64>, name: &str) -> Result<RoleId> {
+    match id {
+        Some(id) if roles.contains_key(&RoleId(id)) => Ok(RoleId(id)),
+        Some(id) => Err(anyhow!(
+            "Role {} no longer exists, pick another with /setup",
+            id
+        )),
+        None => role_by_name(roles, name),
+    }
+}
+
+/// Parses whitespace separated [`MembersPage`]s, as in `MEMBERS_URL`.
+pub fn parse_members_pages(s: &str) -> Result<Vec<MembersPage>> {
+    let pages = s
+        .split_whitespace()
+        .map(str::parse)
+        .collect::<Result<Vec<MembersPage>>>()?;
+    if pages.is_empty() {
+        return Err(anyhow!("No members pages given"));
+    }
+    Ok(pages)
+}
 
 #[derive(Clone)]
 pub struct Config {
-    pub members_url: Url,
+    /// Where memberships come from. Everything but SUMS ignores the members pages and session.
+    pub membership_source: SourceKind,
+    /// The pages memberships are scraped from. Their union is the membership list.
+    pub members_pages: Vec<MembersPage>,
+    /// What the members pages of a guild with its own must start with, as they're scraped with the
+    /// shared session. From `ALLOWED_MEMBERS_URLS`, or the sites of the `MEMBERS_URL` pages.
+    pub allowed_members_urls: Vec<Url>,
+    /// Which memberships this config reads and writes: the ID of a guild with its own members
+    /// pages, or 0 for the ones from `MEMBERS_URL` shared by every other guild.
+    pub scope: u64,
+    /// The guild this config is for, or 0 before a guild's settings are applied.
+    pub guild_id: u64,
+    /// The guild run by whoever runs Bruce, from `HOME_GUILD_ID`, or the only guild if it's unset.
+    pub home_guild_id: Option<u64>,
     pub data_dir: String,
     pub initial_cookie_value: String,
+    pub sums_login: Option<SumsLogin>,
+    pub scrape_layout: ScrapeLayout,
     pub discord_token: String,
+    /// The member role picked with `/setup`, looked for by `member_role_name` until there is one.
+    pub member_role_id: Option<u64>,
     pub member_role_name: String,
+    pub privileged_role_id: Option<u64>,
     pub privileged_role_name: String,
+    pub membership_type_roles: TypeRoles,
+    /// The IDs the guild's `membership_type_roles` were pinned to, by role name.
+    pub type_role_ids: HashMap<String, u64>,
     pub student_id_length: usize,
     pub membership_purchase_url: Option<String>,
+    pub committee_channel_id: Option<u64>,
+    /// Where membership changes are logged for committee.
+    pub audit_channel_id: Option<u64>,
+    pub auto_prune_schedule: Option<PruneSchedule>,
+    pub auto_prune_grace_days: u32,
+    pub session_alert_interval_hours: u32,
+    pub cookie_cipher: CookieCipher,
 }
 
 impl Config {
     pub fn generate() -> Self {
+        let data_dir = std::env::var("DATA_DIR").unwrap_or_else(|_| "/data".to_string());
+        // The key has no default, as one kept next to the database ends up in its backups
+        let cookie_cipher = match std::env::var("COOKIE_KEY")
+            .ok()
+            .filter(|key| !key.is_empty())
+        {
+            Some(key) => CookieCipher::from_base64(&key).expect("valid COOKIE_KEY"),
+            None => {
+                let file = std::env::var("COOKIE_KEY_FILE")
+                    .ok()
+                    .filter(|file| !file.is_empty())
+                    .map(PathBuf::from)
+                    .expect("COOKIE_KEY or COOKIE_KEY_FILE must be set");
+                if file.starts_with(&data_dir) {
+                    log::warn!(
+                        "COOKIE_KEY_FILE is in DATA_DIR, so backups of the database will include the key"
+                    );
+                }
+                CookieCipher::from_file(&file).expect("readable COOKIE_KEY_FILE")
+            }
+        };
+        let var = |key: &str| std::env::var(key).ok().filter(|value| !value.is_empty());
+        let membership_source: SourceKind = var("MEMBERSHIP_SOURCE")
+            .map(|source| source.parse().expect("valid MEMBERSHIP_SOURCE"))
+            .unwrap_or_default();
+        let members_pages = match (var("MEMBERS_URL"), &membership_source) {
+            (Some(url), _) => parse_members_pages(&url).expect("valid MEMBERS_URL"),
+            (None, SourceKind::Sums) => panic!("MEMBERS_URL must be set to scrape SUMS"),
+            (None, _) => vec![],
+        };
+        let sums_login = match (var("SUMS_USERNAME"), var("SUMS_PASSWORD")) {
+            (Some(username), Some(password)) => Some(SumsLogin {
+                url: var("SUMS_LOGIN_URL")
+                    .map(|url| url.parse().expect("valid SUMS_LOGIN_URL"))
+                    .unwrap_or_else(|| {
+                        members_pages
+                            .first()
+                            .expect("SUMS_LOGIN_URL or MEMBERS_URL must be set")
+                            .url
+                            .join("/login")
+                            .expect("valid SUMS_LOGIN_URL")
+                    }),
+                username,
+                password,
+            }),
+            (None, None) => None,
+            _ => panic!("SUMS_USERNAME and SUMS_PASSWORD must be set together"),
+        };
+        let allowed_members_urls = match var("ALLOWED_MEMBERS_URLS") {
+            Some(urls) => urls
+                .split_whitespace()
+                .map(|url| url.parse().expect("valid ALLOWED_MEMBERS_URLS"))
+                .collect(),
+            None => members_pages
+                .iter()
+                .map(|page| page.url.join("/").expect("valid MEMBERS_URL"))
+                .collect(),
+        };
         Self {
-            members_url: std::env::var("MEMBERS_URL")
-                .expect("MEMBERS_URL")
-                .parse::<Url>()
-                .expect("valid MEMBERS_URL"),
-            data_dir: std::env::var("DATA_DIR").unwrap_or_else(|_| "/data".to_string()),
-            initial_cookie_value: std::env::var("INITIAL_SUMS_COOKIE_VALUE")
-                .expect("INITIAL_SUMS_COOKIE_VALUE"),
+            initial_cookie_value: match (&sums_login, &membership_source) {
+                (None, SourceKind::Sums) => {
+                    std::env::var("INITIAL_SUMS_COOKIE_VALUE").expect("INITIAL_SUMS_COOKIE_VALUE")
+                }
+                _ => std::env::var("INITIAL_SUMS_COOKIE_VALUE").unwrap_or_default(),
+            },
+            membership_source,
+            members_pages,
+            allowed_members_urls,
+            scope: 0,
+            guild_id: 0,
+            home_guild_id: var("HOME_GUILD_ID")
+                .map(|id| id.parse().expect("Failed to parse HOME_GUILD_ID as number")),
+            data_dir,
+            sums_login,
+            scrape_layout: {
+                let default = ScrapeLayout::default();
+                ScrapeLayout {
+                    table_selector: var("SCRAPE_TABLE_SELECTOR").unwrap_or(default.table_selector),
+                    row_selector: var("SCRAPE_ROW_SELECTOR").unwrap_or(default.row_selector),
+                    cell_selector: var("SCRAPE_CELL_SELECTOR").unwrap_or(default.cell_selector),
+                    header_selector: var("SCRAPE_HEADER_SELECTOR")
+                        .unwrap_or(default.header_selector),
+                    columns: var("SCRAPE_COLUMNS")
+                        .map(|columns| parse_columns(&columns).expect("valid SCRAPE_COLUMNS"))
+                        .unwrap_or_default(),
+                    next_selector: var("SCRAPE_NEXT_SELECTOR").unwrap_or(default.next_selector),
+                    count_selector: var("SCRAPE_COUNT_SELECTOR").unwrap_or(default.count_selector),
+                    datatable_url: var("SCRAPE_DATATABLE_URL"),
+                }
+                .validate()
+            }
+            .expect("valid SCRAPE_* settings"),
             discord_token: std::env::var("DISCORD_TOKEN").expect("missing DISCORD_TOKEN"),
+            member_role_id: None,
             member_role_name: std::env::var("MEMBER_ROLE_NAME")
                 .unwrap_or_else(|_| "Member".to_string()),
+            privileged_role_id: None,
             privileged_role_name: std::env::var("PRIVILEGED_ROLE_NAME")
                 .unwrap_or_else(|_| "Committee".to_string()),
+            membership_type_roles: var("MEMBERSHIP_TYPE_ROLES")
+                .map(|roles| roles.parse().expect("valid MEMBERSHIP_TYPE_ROLES"))
+                .unwrap_or_default(),
+            type_role_ids: HashMap::new(),
             student_id_length: std::env::var("STUDENT_ID_LENGTH")
                 .unwrap_or_else(|_| 8.to_string())
                 .parse()
                 .expect("Failed to parse STUDENT_ID_LENGTH as number"),
             membership_purchase_url: std::env::var("MEMBERSHIP_PURCHASE_URL").ok(),
+            committee_channel_id: var("COMMITTEE_CHANNEL_ID").map(|id| {
+                id.parse()
+                    .expect("Failed to parse COMMITTEE_CHANNEL_ID as number")
+            }),
+            audit_channel_id: var("AUDIT_CHANNEL_ID").map(|id| {
+                id.parse()
+                    .expect("Failed to parse AUDIT_CHANNEL_ID as number")
+            }),
+            auto_prune_schedule: std::env::var("AUTO_PRUNE_SCHEDULE")
+                .ok()
+                .filter(|schedule| !schedule.is_empty())
+                .map(|schedule| schedule.parse().expect("valid AUTO_PRUNE_SCHEDULE")),
+            auto_prune_grace_days: std::env::var("AUTO_PRUNE_GRACE_DAYS")
+                .unwrap_or_else(|_| 14.to_string())
+                .parse()
+                .expect("Failed to parse AUTO_PRUNE_GRACE_DAYS as number"),
+            session_alert_interval_hours: std::env::var("SESSION_ALERT_INTERVAL_HOURS")
+                .unwrap_or_else(|_| 24.to_string())
+                .parse()
+                .expect("Failed to parse SESSION_ALERT_INTERVAL_HOURS as number"),
+            cookie_cipher,
+        }
+    }
+
+    /// A config for tests that don't talk to SUMS, with its database in `data_dir`.
+    #[cfg(test)]
+    pub fn test(data_dir: &std::path::Path) -> Self {
+        Self {
+            membership_source: SourceKind::Sums,
+            members_pages: parse_members_pages("https://sums.su/groups/336/members").unwrap(),
+            allowed_members_urls: vec!["https://sums.su/".parse().unwrap()],
+            scope: 0,
+            guild_id: 0,
+            home_guild_id: None,
+            data_dir: data_dir.to_str().unwrap().to_string(),
+            initial_cookie_value: "".to_string(),
+            sums_login: None,
+            scrape_layout: Default::default(),
+            discord_token: "".to_string(),
+            member_role_id: None,
+            member_role_name: "Member".to_string(),
+            privileged_role_id: None,
+            privileged_role_name: "Committee".to_string(),
+            membership_type_roles: Default::default(),
+            type_role_ids: HashMap::new(),
+            student_id_length: 8,
+            membership_purchase_url: None,
+            committee_channel_id: None,
+            audit_channel_id: None,
+            auto_prune_schedule: None,
+            auto_prune_grace_days: 14,
+            session_alert_interval_hours: 24,
+            cookie_cipher: CookieCipher::from_base64(crate::fake_sums::TEST_COOKIE_KEY).unwrap(),
+        }
+    }
+
+    /// This config with `guild_id`'s `/config` settings applied.
+    pub fn for_guild(&self, guild_id: u64) -> Result<Config> {
+        let conn = self.get_sqlite_conn()?;
+        let mut config = GuildConfig::get(&conn, guild_id)?.apply(self)?;
+        config.type_role_ids = type_roles::pinned(&conn, guild_id)?;
+        Ok(config)
+    }
+
+    /// Whether the guild can look up memberships nobody has registered in it, which only the guild
+    /// that owns them, or the home guild for the shared ones, should.
+    pub fn sees_all_memberships(&self) -> bool {
+        self.scope == self.guild_id || self.home_guild_id == Some(self.guild_id)
+    }
+
+    /// The member role among a guild's `roles`.
+    pub fn member_role(&self, roles: &HashMap<RoleId, Role>) -> Result<RoleId> {
+        find_role(roles, self.member_role_id, &self.member_role_name)
+    }
+
+    /// The `membership_type_roles` among a guild's `roles`.
+    pub fn type_role_map(&self, roles: &HashMap<RoleId, Role>) -> RoleMap {
+        self.membership_type_roles
+            .resolve(roles, &self.type_role_ids)
+    }
+
+    /// The role allowed to run committee commands among a guild's `roles`.
+    pub fn privileged_role(&self, roles: &HashMap<RoleId, Role>) -> Result<RoleId> {
+        find_role(roles, self.privileged_role_id, &self.privileged_role_name)
+    }
+
+    /// Checks `pages` set for a guild with `/config` are allowed by `allowed_members_urls`.
+    pub fn check_members_pages(&self, pages: &[MembersPage]) -> Result<()> {
+        for page in pages {
+            if !self
+                .allowed_members_urls
+                .iter()
+                .any(|allowed| page.url.as_str().starts_with(allowed.as_str()))
+            {
+                return Err(anyhow!(
+                    "{} isn't one of the members pages allowed by whoever runs Bruce",
+                    page.url
+                ));
+            }
         }
+        Ok(())
+    }
+
+    /// The page the SUMS session cookie is tested and stored against. Every members page is
+    /// expected to be on the same student dashboard, so they all share it.
+    pub fn session_url(&self) -> &Url {
+        &self.members_pages[0].url
+    }
+
+    /// Whether `channel_id` is in `guild_id`. Channels set in the environment only belong to one
+    /// of the servers, so the others skip them.
+    pub async fn channel_in_guild(&self, channel_id: ChannelId, guild_id: GuildId) -> Result<bool> {
+        let channel_guild = channel_id
+            .to_channel(&self.get_http())
+            .await?
+            .guild()
+            .map(|channel| channel.guild_id);
+        Ok(channel_guild == Some(guild_id))
     }
 
     pub fn get_http(&self) -> Http {
@@ -53,7 +358,74 @@ impl Config {
 
     pub fn get_sqlite_conn(&self) -> Result<Connection> {
         let file = self.get_sqlite_file();
-        OpenOptions::new().create(true).write(true).open(&file)?;
+        OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&file)?;
         Ok(Connection::open(&file)?)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{find_role, parse_members_pages, Config};
+    use crate::type_roles::tests::role;
+    use poise::serenity_prelude::{Role, RoleId};
+    use std::collections::HashMap;
+
+    #[test]
+    fn members_pages() {
+        let pages = parse_members_pages(
+            "https://sums.su/groups/336/members?sort=name\n  alumni=https://sums.su/groups/400/members",
+        )
+        .unwrap();
+        assert_eq!(pages.len(), 2);
+        assert_eq!(
+            pages[0].url.as_str(),
+            "https://sums.su/groups/336/members?sort=name"
+        );
+        assert_eq!(pages[0].tag, None);
+        assert_eq!(pages[1].tag.as_deref(), Some("alumni"));
+        assert!(parse_members_pages("").is_err());
+        assert!(parse_members_pages("=https://sums.su/groups/400/members").is_err());
+        assert!(parse_members_pages("a,b=https://sums.su/groups/400/members").is_err());
+        assert!(parse_members_pages("alumni=not a url").is_err());
+    }
+
+    #[test]
+    fn roles_by_id_then_name() {
+        let roles: HashMap<RoleId, Role> = [
+            role(1, "Member"),
+            role(2, "Committee"),
+            role(3, "Committee"),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(find_role(&roles, None, "Member").unwrap(), RoleId(1));
+        assert_eq!(find_role(&roles, Some(1), "Renamed").unwrap(), RoleId(1));
+        assert!(find_role(&roles, Some(9), "Member").is_err());
+        assert!(find_role(&roles, None, "Committee").is_err());
+        assert_eq!(find_role(&roles, Some(3), "Committee").unwrap(), RoleId(3));
+    }
+
+    #[test]
+    fn allowed_members_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            allowed_members_urls: vec!["https://sums.su/groups/400/".parse().unwrap()],
+            ..Config::test(dir.path())
+        };
+        let allowed = |pages: &str| {
+            config
+                .check_members_pages(&parse_members_pages(pages).unwrap())
+                .is_ok()
+        };
+        assert!(allowed("https://sums.su/groups/400/members"));
+        assert!(!allowed("https://sums.su/groups/4000/members"));
+        assert!(!allowed(
+            "https://sums.su/groups/400/members https://sums.su/groups/336/members"
+        ));
+        assert!(!allowed("https://sums.su.example.com/groups/400/members"));
+    }
+}
@@ -0,0 +1,129 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+
+use aes_gcm::aead::{Aead, KeyInit, OsRng};
+use aes_gcm::{AeadCore, Aes256Gcm, Key, Nonce};
+use anyhow::{anyhow, Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+/// Marks a stored value as encrypted, so plaintext values from older versions can be told apart.
+const PREFIX: &str = "enc1:";
+const NONCE_LEN: usize = 12;
+
+/// The AES-256-GCM key cookie values are encrypted with before they reach the database.
+#[derive(Clone)]
+pub struct CookieCipher {
+    cipher: Aes256Gcm,
+}
+
+impl CookieCipher {
+    /// Parses a base64 encoded 32 byte key.
+    pub fn from_base64(key: &str) -> Result<Self> {
+        let key = STANDARD
+            .decode(key.trim())
+            .context("Cookie key is not valid base64")?;
+        if key.len() != 32 {
+            return Err(anyhow!(
+                "Cookie key must be 32 bytes, got {} bytes",
+                key.len()
+            ));
+        }
+        Ok(Self {
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)),
+        })
+    }
+
+    /// Reads the key from `path`, generating a new one there if the file doesn't exist yet.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            log::warn!(
+                "Generating a new cookie key at {}, keep it out of database backups",
+                path.display()
+            );
+            let key = STANDARD.encode(Aes256Gcm::generate_key(OsRng));
+            let mut options = OpenOptions::new();
+            options.write(true).create_new(true);
+            // Only readable by us from the moment it exists
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            options
+                .open(path)
+                .and_then(|mut file| file.write_all(key.as_bytes()))
+                .with_context(|| format!("Failed to write cookie key to {}", path.display()))?;
+        }
+        let key = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read cookie key from {}", path.display()))?;
+        Self::from_base64(&key)
+    }
+
+    pub fn encrypt(&self, value: &str) -> Result<String> {
+        let nonce = Aes256Gcm::generate_nonce(OsRng);
+        let mut data = nonce.to_vec();
+        data.extend(
+            self.cipher
+                .encrypt(&nonce, value.as_bytes())
+                .map_err(|_| anyhow!("Failed to encrypt cookie"))?,
+        );
+        Ok(format!("{}{}", PREFIX, STANDARD.encode(data)))
+    }
+
+    pub fn decrypt(&self, value: &str) -> Result<String> {
+        let data = STANDARD.decode(
+            value
+                .strip_prefix(PREFIX)
+                .ok_or_else(|| anyhow!("Cookie is not encrypted"))?,
+        )?;
+        if data.len() < NONCE_LEN {
+            return Err(anyhow!("Encrypted cookie is truncated"));
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        let plaintext = self
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow!("Failed to decrypt cookie, has the cookie key changed?"))?;
+        Ok(String::from_utf8(plaintext)?)
+    }
+
+    pub fn is_encrypted(value: &str) -> bool {
+        value.starts_with(PREFIX)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cookie_cipher::CookieCipher;
+    use crate::fake_sums::TEST_COOKIE_KEY as KEY;
+
+    #[test]
+    fn round_trip() {
+        let cipher = CookieCipher::from_base64(KEY).unwrap();
+        let encrypted = cipher.encrypt("session").unwrap();
+        assert!(CookieCipher::is_encrypted(&encrypted));
+        assert!(!encrypted.contains("session"));
+        assert_ne!(encrypted, cipher.encrypt("session").unwrap());
+        assert_eq!(cipher.decrypt(&encrypted).unwrap(), "session");
+
+        let other = CookieCipher::from_base64(&KEY.replace('M', "N")).unwrap();
+        assert!(other.decrypt(&encrypted).is_err());
+        assert!(cipher.decrypt("session").is_err());
+        assert!(CookieCipher::from_base64("c2hvcnQ=").is_err());
+    }
+
+    #[test]
+    fn key_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cookie.key");
+        let cipher = CookieCipher::from_file(&path).unwrap();
+        let encrypted = cipher.encrypt("session").unwrap();
+        let reloaded = CookieCipher::from_file(&path).unwrap();
+        assert_eq!(reloaded.decrypt(&encrypted).unwrap(), "session");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+}
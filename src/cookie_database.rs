@@ -1,40 +1,243 @@
 use std::string::String;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use cookie::Cookie;
 use fallible_iterator::FallibleIterator;
 use reqwest::cookie::CookieStore;
 use reqwest::header::HeaderValue;
 use reqwest::Url;
-use rusqlite::{params, Connection};
+use rusqlite::{params, Connection, Row};
 
+use crate::cookie_cipher::CookieCipher;
+use crate::membership::unix_now;
+
+/// A cookie as kept in the `cookies` table, with the attributes RFC 6265 needs to decide which
+/// requests it's sent with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredCookie {
+    pub domain: String,
+    pub path: String,
+    pub name: String,
+    pub value: String,
+    /// Only send the cookie to `domain` itself, not its subdomains (no `Domain` attribute).
+    pub host_only: bool,
+    pub secure: bool,
+    pub http_only: bool,
+    /// Unix timestamp the cookie expires at, or `None` for a session cookie.
+    pub expires: Option<i64>,
+}
+
+impl StoredCookie {
+    /// Parses a `Set-Cookie` header received from `url`, following RFC 6265 section 5.3.
+    pub fn parse(header: &str, url: &Url) -> Result<Self> {
+        let cookie = Cookie::parse(header)?;
+        let host = url
+            .host_str()
+            .ok_or_else(|| anyhow!("Cookie URL {} has no host", url))?
+            .to_lowercase();
+        let (domain, host_only) = match cookie.domain() {
+            Some(domain) if !domain.is_empty() => {
+                let domain = domain.trim_start_matches('.').to_lowercase();
+                if !domain_matches(&host, &domain) {
+                    return Err(anyhow!(
+                        "Cookie {} for domain {} rejected from {}",
+                        cookie.name(),
+                        domain,
+                        host
+                    ));
+                }
+                (domain, false)
+            }
+            _ => (host, true),
+        };
+        let path = match cookie.path() {
+            Some(path) if path.starts_with('/') => path.to_string(),
+            _ => default_path(url),
+        };
+        let expires = match (cookie.max_age(), cookie.expires_datetime()) {
+            (Some(max_age), _) => Some(unix_now() + max_age.whole_seconds()),
+            (None, Some(expires)) => Some(expires.unix_timestamp()),
+            (None, None) => None,
+        };
+        Ok(Self {
+            domain,
+            path,
+            name: cookie.name().to_string(),
+            value: cookie.value().to_string(),
+            host_only,
+            secure: cookie.secure().unwrap_or(false),
+            http_only: cookie.http_only().unwrap_or(false),
+            expires,
+        })
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires.is_some_and(|expires| expires <= unix_now())
+    }
+
+    /// Whether this cookie should be sent with a request to `url`.
+    pub fn matches(&self, url: &Url) -> bool {
+        let host = match url.host_str() {
+            Some(host) => host.to_lowercase(),
+            None => return false,
+        };
+        let domain_ok = if self.host_only {
+            host == self.domain
+        } else {
+            domain_matches(&host, &self.domain)
+        };
+        domain_ok
+            && path_matches(url.path(), &self.path)
+            && (!self.secure || url.scheme() == "https")
+            && !self.is_expired()
+    }
+
+    fn from_row(r: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            domain: r.get(0)?,
+            path: r.get(1)?,
+            name: r.get(2)?,
+            value: r.get(3)?,
+            host_only: r.get(4)?,
+            secure: r.get(5)?,
+            http_only: r.get(6)?,
+            expires: r.get(7)?,
+        })
+    }
+}
+
+fn domain_matches(host: &str, domain: &str) -> bool {
+    host == domain
+        || (host.ends_with(domain)
+            && host[..host.len() - domain.len()].ends_with('.')
+            && host.parse::<std::net::IpAddr>().is_err())
+}
+
+fn path_matches(request_path: &str, cookie_path: &str) -> bool {
+    request_path == cookie_path
+        || (request_path.starts_with(cookie_path)
+            && (cookie_path.ends_with('/') || request_path[cookie_path.len()..].starts_with('/')))
+}
+
+fn default_path(url: &Url) -> String {
+    match url.path().rfind('/') {
+        Some(0) | None => "/".to_string(),
+        Some(i) => url.path()[..i].to_string(),
+    }
+}
+
+/// The `cookies` table, with values encrypted by `cipher` at rest.
 pub struct CookieDatabase {
     conn: Connection,
+    cipher: CookieCipher,
 }
 
 impl CookieDatabase {
-    pub fn init_table(conn: &Connection) -> Result<()> {
-        conn.execute("CREATE TABLE IF NOT EXISTS cookies (url VARCHAR NOT NULL PRIMARY KEY, name VARCHAR NOT NULL, value VARCHAR NOT NULL)", params![])?;
-        Ok(())
+    /// Wraps `conn`, first encrypting any values left in plaintext by older versions.
+    pub fn new(conn: Connection, cipher: CookieCipher) -> Result<Self> {
+        let db = Self { conn, cipher };
+        let plaintext: Vec<StoredCookie> = db
+            .conn
+            .prepare(
+                "SELECT domain, path, name, value, host_only, secure, http_only, expires FROM cookies",
+            )?
+            .query(params![])?
+            .map(StoredCookie::from_row)
+            .filter(|c| Ok(!CookieCipher::is_encrypted(&c.value)))
+            .collect()?;
+        for cookie in plaintext {
+            db.store(&cookie)?;
+        }
+        Ok(db)
     }
 
-    pub fn new(conn: Connection) -> Self {
-        Self { conn }
+    /// Stores a session cookie for the host of `url`, as if the server had set it.
+    pub fn add_cookie<T: Into<String>>(&self, url: &Url, key: T, value: T) -> Result<()> {
+        self.store(&StoredCookie {
+            domain: url
+                .host_str()
+                .ok_or_else(|| anyhow!("Cookie URL {} has no host", url))?
+                .to_lowercase(),
+            path: "/".to_string(),
+            name: key.into(),
+            value: value.into(),
+            host_only: true,
+            secure: false,
+            http_only: true,
+            expires: None,
+        })
     }
 
-    pub fn add_cookie<T: Into<String>>(&self, url: &Url, key: T, value: T) -> Result<()> {
+    pub fn get_cookie_value(&self, url: &Url, name: &str) -> Result<String> {
+        self.matching(url)?
+            .into_iter()
+            .find(|c| c.name == name)
+            .map(|c| c.value)
+            .ok_or_else(|| anyhow!("No {} cookie stored for {}", name, url))
+    }
+
+    pub fn store(&self, cookie: &StoredCookie) -> Result<()> {
+        // Sweep out expired cookies here rather than on every request, which only reads
+        self.conn.execute(
+            "DELETE FROM cookies WHERE expires <= ?1",
+            params![unix_now()],
+        )?;
+        if cookie.is_expired() {
+            self.conn.execute(
+                "DELETE FROM cookies WHERE domain = ?1 AND path = ?2 AND name = ?3",
+                params![cookie.domain, cookie.path, cookie.name],
+            )?;
+            return Ok(());
+        }
         self.conn.execute(
-            "INSERT OR REPLACE INTO cookies (url, name, value) VALUES (?1, ?2, ?3)",
-            params![url.to_string(), key.into(), value.into()],
+            "INSERT OR REPLACE INTO cookies (domain, path, name, value, host_only, secure, http_only, expires) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                cookie.domain,
+                cookie.path,
+                cookie.name,
+                self.cipher.encrypt(&cookie.value)?,
+                cookie.host_only,
+                cookie.secure,
+                cookie.http_only,
+                cookie.expires
+            ],
         )?;
         Ok(())
     }
 
-    pub fn get_cookie_value(&self, url: &Url) -> Result<String> {
-        Ok(self.conn.query_row(
-            "SELECT value FROM cookies WHERE url = ?1",
-            params![url.to_string()],
-            |r| r.get(0),
-        )?)
+    /// The unexpired cookies to send with a request to `url`, longest path first.
+    pub fn matching(&self, url: &Url) -> Result<Vec<StoredCookie>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT domain, path, name, value, host_only, secure, http_only, expires FROM cookies WHERE expires IS NULL OR expires > ?1",
+        )?;
+        let stored: Vec<StoredCookie> = stmt
+            .query(params![unix_now()])?
+            .map(StoredCookie::from_row)
+            .filter(|c| Ok(c.matches(url)))
+            .collect()?;
+        let mut cookies = vec![];
+        for mut cookie in stored {
+            match self.cipher.decrypt(&cookie.value) {
+                Ok(value) => {
+                    cookie.value = value;
+                    cookies.push(cookie);
+                }
+                Err(err) => log::warn!(
+                    "Skipping cookie {} for {}: {}",
+                    cookie.name,
+                    cookie.domain,
+                    err
+                ),
+            }
+        }
+        cookies.sort_by_key(|c| std::cmp::Reverse(c.path.len()));
+        Ok(cookies)
+    }
+
+    fn set_cookie(&self, header: &HeaderValue, url: &Url) -> Result<()> {
+        let cookie = StoredCookie::parse(header.to_str()?, url)?;
+        log::info!("Storing cookie {} for {}", cookie.name, cookie.domain);
+        self.store(&cookie)
     }
 }
 
@@ -43,38 +246,29 @@ unsafe impl Sync for CookieDatabase {}
 impl CookieStore for CookieDatabase {
     fn set_cookies(&self, cookie_headers: &mut dyn Iterator<Item = &HeaderValue>, url: &Url) {
         for header in cookie_headers {
-            let header = header
-                .to_str()
-                .unwrap_or_else(|_| panic!("converting header to str {:?}", header));
-            log::info!("Storing header: {}", header);
-            let mut header = header
-                .split(';')
-                .next()
-                .expect("getting key value pair from header")
-                .split('=');
-            if let Err(err) = self.add_cookie(
-                url,
-                header.next().expect("getting key from header"),
-                header.next().expect("getting value from header"),
-            ) {
-                log::error!("{}", err);
+            if let Err(err) = self.set_cookie(header, url) {
+                log::warn!("Ignoring Set-Cookie header from {}: {}", url, err);
             }
         }
     }
 
     fn cookies(&self, url: &Url) -> Option<HeaderValue> {
+        let cookies = match self.matching(url) {
+            Ok(cookies) => cookies,
+            Err(err) => {
+                log::error!("Failed to load cookies for {}: {}", url, err);
+                return None;
+            }
+        };
+        if cookies.is_empty() {
+            return None;
+        }
         HeaderValue::from_str(
-            &self
-                .conn
-                .prepare("SELECT name, value FROM cookies WHERE url = ?1")
-                .expect("preparing cookie fetch statement")
-                .query(params![url.as_str()])
-                .expect("running cookie fetch query")
-                .map(|r| Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?)))
-                .map(|(name, value)| Ok(format!("{}={};", name, value)))
-                .collect::<Vec<String>>()
-                .expect("gathering cookies into header string")
-                .join(";"),
+            &cookies
+                .iter()
+                .map(|c| format!("{}={}", c.name, c.value))
+                .collect::<Vec<_>>()
+                .join("; "),
         )
         .ok()
     }
@@ -82,26 +276,182 @@ impl CookieStore for CookieDatabase {
 
 #[cfg(test)]
 mod tests {
+    use crate::cookie_cipher::CookieCipher;
     use crate::cookie_database::CookieDatabase;
+    use crate::fake_sums::TEST_COOKIE_KEY;
+    use crate::migrations::migrate;
     use reqwest::cookie::CookieStore;
     use reqwest::header::HeaderValue;
     use reqwest::Url;
-    use rusqlite::Connection;
+    use rusqlite::{params, Connection};
     use std::env::temp_dir;
 
+    fn database() -> CookieDatabase {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        CookieDatabase::new(conn, cipher()).unwrap()
+    }
+
+    fn cipher() -> CookieCipher {
+        CookieCipher::from_base64(TEST_COOKIE_KEY).unwrap()
+    }
+
+    fn set(db: &CookieDatabase, url: &str, headers: &[&str]) {
+        let headers: Vec<HeaderValue> = headers
+            .iter()
+            .map(|h| HeaderValue::from_str(h).unwrap())
+            .collect();
+        db.set_cookies(&mut headers.iter(), &Url::parse(url).unwrap());
+    }
+
+    fn get(db: &CookieDatabase, url: &str) -> Option<String> {
+        db.cookies(&Url::parse(url).unwrap())
+            .map(|h| h.to_str().unwrap().to_string())
+    }
+
     #[test]
     fn in_out() {
         let mut test_db = temp_dir();
         test_db.push("test");
         test_db.set_extension("db");
         std::fs::remove_file(&test_db).unwrap_or(());
-        let db = CookieDatabase::new(Connection::open(test_db).unwrap());
+        let mut conn = Connection::open(test_db).unwrap();
+        migrate(&mut conn).unwrap();
+        let db = CookieDatabase::new(conn, cipher()).unwrap();
 
         let url = Url::parse("https://test.com").unwrap();
-        let header_values = vec![HeaderValue::from_str("test=1234").unwrap()]; // dyn Iterator<Item = &HeaderValue>
+        let header_values = [HeaderValue::from_str("test=1234").unwrap()]; // dyn Iterator<Item = &HeaderValue>
         db.set_cookies(&mut header_values.iter(), &url);
         let output = db.cookies(&url);
         assert!(output.is_some());
-        assert_eq!(output.unwrap().to_str().unwrap(), "test=1234;");
+        assert_eq!(output.unwrap().to_str().unwrap(), "test=1234");
+    }
+
+    #[test]
+    fn multiple_cookies_per_domain() {
+        let db = database();
+        set(
+            &db,
+            "https://test.com/groups/1/members",
+            &["a=1; Path=/", "b=2; Path=/groups"],
+        );
+        assert_eq!(
+            get(&db, "https://test.com/groups/1/members").as_deref(),
+            Some("b=2; a=1")
+        );
+        assert_eq!(get(&db, "https://test.com/").as_deref(), Some("a=1"));
+        assert_eq!(get(&db, "https://test.com/groupsx").as_deref(), Some("a=1"));
+        assert_eq!(get(&db, "https://other.com/"), None);
+    }
+
+    #[test]
+    fn domain_attribute() {
+        let db = database();
+        set(
+            &db,
+            "https://student-dashboard.sums.su/",
+            &[
+                "wide=1; Domain=.sums.su",
+                "narrow=2",
+                "evil=3; Domain=example.com",
+            ],
+        );
+        assert_eq!(
+            get(&db, "https://student-dashboard.sums.su/").as_deref(),
+            Some("wide=1; narrow=2")
+        );
+        assert_eq!(get(&db, "https://shop.sums.su/").as_deref(), Some("wide=1"));
+        assert_eq!(get(&db, "https://example.com/"), None);
+    }
+
+    #[test]
+    fn expiry_and_secure() {
+        let db = database();
+        let url = "https://test.com/";
+        set(
+            &db,
+            url,
+            &[
+                "session=1",
+                "short=2; Max-Age=3600",
+                "gone=3; Expires=Wed, 21 Oct 2015 07:28:00 GMT",
+                "secure=4; Secure",
+            ],
+        );
+        assert_eq!(
+            get(&db, url).as_deref(),
+            Some("session=1; short=2; secure=4")
+        );
+        assert_eq!(
+            get(&db, "http://test.com/").as_deref(),
+            Some("session=1; short=2")
+        );
+        set(&db, url, &["short=; Max-Age=0"]);
+        assert_eq!(get(&db, url).as_deref(), Some("session=1; secure=4"));
+
+        // Reading cookies never writes, expired ones are only filtered out until the next store
+        db.conn
+            .execute(
+                "UPDATE cookies SET expires = 1 WHERE name = 'session'",
+                params![],
+            )
+            .unwrap();
+        assert_eq!(get(&db, url).as_deref(), Some("secure=4"));
+        let count = |db: &CookieDatabase| -> i64 {
+            db.conn
+                .query_row("SELECT COUNT(*) FROM cookies", params![], |r| r.get(0))
+                .unwrap()
+        };
+        assert_eq!(count(&db), 2);
+        set(&db, url, &["other=5"]);
+        assert_eq!(count(&db), 2);
+    }
+
+    #[test]
+    fn malformed_headers() {
+        let db = database();
+        let url = Url::parse("https://test.com/").unwrap();
+        let headers = [
+            HeaderValue::from_bytes(b"bad=\xff").unwrap(),
+            HeaderValue::from_str("no equals sign").unwrap(),
+            HeaderValue::from_str("=").unwrap(),
+            HeaderValue::from_str("good=1").unwrap(),
+        ];
+        db.set_cookies(&mut headers.iter(), &url);
+        assert_eq!(db.cookies(&url).unwrap().to_str().unwrap(), "good=1");
+    }
+
+    #[test]
+    fn encrypted_at_rest() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("db.sqlite");
+        let mut conn = Connection::open(&file).unwrap();
+        migrate(&mut conn).unwrap();
+        conn.execute(
+            "INSERT INTO cookies (domain, path, name, value, host_only, secure, http_only) VALUES ('test.com', '/', 'legacy', 'plain', 1, 0, 1)",
+            [],
+        )
+        .unwrap();
+        let db = CookieDatabase::new(conn, cipher()).unwrap();
+        set(&db, "https://test.com/", &["su_session=secret"]);
+        assert_eq!(
+            get(&db, "https://test.com/").as_deref(),
+            Some("legacy=plain; su_session=secret")
+        );
+
+        let values: Vec<String> = Connection::open(&file)
+            .unwrap()
+            .prepare("SELECT value FROM cookies")
+            .unwrap()
+            .query_map([], |r| r.get(0))
+            .unwrap()
+            .map(|v| v.unwrap())
+            .collect();
+        assert_eq!(values.len(), 2);
+        assert!(values.iter().all(|v| CookieCipher::is_encrypted(v)));
+
+        let other = CookieCipher::from_base64(&TEST_COOKIE_KEY.replace('M', "N")).unwrap();
+        let db = CookieDatabase::new(Connection::open(&file).unwrap(), other).unwrap();
+        assert_eq!(get(&db, "https://test.com/"), None);
     }
 }
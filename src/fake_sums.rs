@@ -0,0 +1,373 @@
+//! An in-process stand-in for the SUMS student dashboard, used to drive the scraper end to end.
+
+use std::sync::{Arc, Mutex};
+
+use reqwest::Url;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+use crate::config::{Config, MembersPage, SumsLogin};
+
+pub const MEMBERS_PATH: &str = "/groups/336/members";
+pub const LOGIN_PATH: &str = "/login";
+pub const DATATABLE_PATH: &str = "/groups/336/members/data";
+const CSRF_TOKEN: &str = "csrf-token";
+pub const TEST_COOKIE_KEY: &str = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=";
+const NOT_AUTHENTICATED_PAGE: &str =
+    "<html><body><h1>Sorry you're not authenticated to view this page</h1></body></html>";
+
+#[derive(Default)]
+struct State {
+    members: Vec<(u32, String)>,
+    session: String,
+    rotate_to: Option<String>,
+    status: Option<u16>,
+    login_redirect: bool,
+    credentials: Option<(String, String)>,
+    received_cookies: Vec<String>,
+    page_size: Option<usize>,
+    datatable: bool,
+    advertised: Option<usize>,
+    hide_totals: bool,
+}
+
+/// A fake student dashboard listening on a random local port.
+///
+/// Requests for [`MEMBERS_PATH`] carrying `su_session=<session>` get the members table, anything
+/// else gets the "not authenticated" page, mirroring what SUMS does with a dead cookie.
+#[derive(Clone)]
+pub struct FakeSums {
+    url: Url,
+    state: Arc<Mutex<State>>,
+}
+
+impl FakeSums {
+    pub async fn start(session: &str) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind fake SUMS listener");
+        let url = Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+        let state = Arc::new(Mutex::new(State {
+            session: session.to_string(),
+            ..Default::default()
+        }));
+        let server = Self { url, state };
+        let handler = server.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let handler = handler.clone();
+                tokio::spawn(async move {
+                    let mut buf = vec![0; 8192];
+                    let mut len = 0;
+                    while !buf[..len].windows(4).any(|w| w == b"\r\n\r\n") {
+                        match stream.read(&mut buf[len..]).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => len += n,
+                        }
+                    }
+                    let mut request = String::from_utf8_lossy(&buf[..len]).to_string();
+                    let header_len = request.find("\r\n\r\n").unwrap() + 4;
+                    let content_length = request
+                        .lines()
+                        .find_map(|l| {
+                            let (name, value) = l.split_once(':')?;
+                            name.eq_ignore_ascii_case("content-length")
+                                .then(|| value.trim().parse().ok())?
+                        })
+                        .unwrap_or(0);
+                    while len < header_len + content_length {
+                        match stream.read(&mut buf[len..]).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => len += n,
+                        }
+                        request = String::from_utf8_lossy(&buf[..len]).to_string();
+                    }
+                    let response = handler.respond(&request);
+                    let _ = stream.write_all(response.as_bytes()).await;
+                    let _ = stream.shutdown().await;
+                });
+            }
+        });
+        server
+    }
+
+    pub fn members_url(&self) -> Url {
+        self.url.join(MEMBERS_PATH).unwrap()
+    }
+
+    /// The members page as a [`Config`] entry, tagged with `tag`.
+    pub fn members_page(&self, tag: Option<&str>) -> MembersPage {
+        MembersPage {
+            url: self.members_url(),
+            tag: tag.map(str::to_string),
+        }
+    }
+
+    pub fn set_members(&self, members: &[(u32, &str)]) {
+        self.state.lock().unwrap().members = members
+            .iter()
+            .map(|(id, name)| (*id, name.to_string()))
+            .collect();
+    }
+
+    /// Makes the next authenticated response rotate the session to `session` via Set-Cookie.
+    pub fn rotate_session(&self, session: &str) {
+        self.state.lock().unwrap().rotate_to = Some(session.to_string());
+    }
+
+    /// Expires the current session server side, as SUMS does after a while.
+    pub fn set_session(&self, session: &str) {
+        self.state.lock().unwrap().session = session.to_string();
+    }
+
+    /// Makes unauthenticated requests redirect to a login page instead of the error page.
+    pub fn set_login_redirect(&self, login_redirect: bool) {
+        self.state.lock().unwrap().login_redirect = login_redirect;
+    }
+
+    /// Accepts logins through the form at [`LOGIN_PATH`] with these credentials.
+    pub fn set_credentials(&self, username: &str, password: &str) {
+        self.state.lock().unwrap().credentials = Some((username.to_string(), password.to_string()));
+    }
+
+    /// Makes every response use `status` instead of 200.
+    pub fn set_status(&self, status: Option<u16>) {
+        self.state.lock().unwrap().status = status;
+    }
+
+    /// Splits the members table into pages of `page_size` rows linked by `rel="next"`, with a
+    /// DataTables style "Showing x to y of z entries" count.
+    pub fn set_page_size(&self, page_size: Option<usize>) {
+        self.state.lock().unwrap().page_size = page_size;
+    }
+
+    /// Serves an empty table whose rows are loaded from a DataTables endpoint at
+    /// [`DATATABLE_PATH`], which honours the page size if one is set.
+    pub fn set_datatable(&self, datatable: bool) {
+        self.state.lock().unwrap().datatable = datatable;
+    }
+
+    /// Leaves the record counts out of datatable responses, as some endpoints do.
+    pub fn set_totals(&self, totals: bool) {
+        self.state.lock().unwrap().hide_totals = !totals;
+    }
+
+    /// Makes the member count shown on paged tables `advertised` instead of the real count.
+    pub fn set_advertised(&self, advertised: Option<usize>) {
+        self.state.lock().unwrap().advertised = advertised;
+    }
+
+    /// The `Cookie` headers received so far, in order.
+    pub fn received_cookies(&self) -> Vec<String> {
+        self.state.lock().unwrap().received_cookies.clone()
+    }
+
+    /// Credentials for this server's login form.
+    pub fn login(&self, username: &str, password: &str) -> SumsLogin {
+        SumsLogin {
+            url: self.url.join(LOGIN_PATH).unwrap(),
+            username: username.to_string(),
+            password: password.to_string(),
+        }
+    }
+
+    /// A config pointed at this server, with its database in `data_dir`.
+    pub fn config(&self, data_dir: &std::path::Path, initial_cookie_value: &str) -> Config {
+        Config {
+            members_pages: vec![self.members_page(None)],
+            initial_cookie_value: initial_cookie_value.to_string(),
+            ..Config::test(data_dir)
+        }
+    }
+
+    /// Serves a login form guarded by a CSRF token, logging in with the current session on a
+    /// correct POST.
+    fn respond_login(state: &mut State, request: &str) -> String {
+        let form = format!(
+            "<html><body><form method=\"post\" action=\"{}\"><input type=\"hidden\" name=\"_token\" value=\"{}\"><input type=\"email\" name=\"email\"><input type=\"password\" name=\"password\"><input type=\"checkbox\" name=\"remember\"><input type=\"submit\" value=\"Log in\"></form></body></html>",
+            LOGIN_PATH, CSRF_TOKEN
+        );
+        if !request.starts_with("POST") {
+            return http_response(200, &[], &form);
+        }
+        let body = request.split("\r\n\r\n").nth(1).unwrap_or_default();
+        let fields: Vec<(&str, &str)> = body.split('&').filter_map(|f| f.split_once('=')).collect();
+        let field = |name| fields.iter().find(|(n, _)| *n == name).map(|(_, v)| *v);
+        let accepted = match &state.credentials {
+            Some((username, password)) => {
+                field("_token") == Some(CSRF_TOKEN)
+                    && field("email").map(|u| u.replace("%40", "@")).as_deref() == Some(username)
+                    && field("password") == Some(password.as_str())
+                    && field("remember").is_none()
+            }
+            None => false,
+        };
+        if !accepted {
+            return http_response(200, &[], &form);
+        }
+        http_response(
+            302,
+            &[
+                format!("Set-Cookie: su_session={}; path=/; HttpOnly", state.session),
+                format!("Location: {}", MEMBERS_PATH),
+            ],
+            "",
+        )
+    }
+
+    fn respond(&self, request: &str) -> String {
+        let mut state = self.state.lock().unwrap();
+        let target = request.split_whitespace().nth(1).unwrap_or("/");
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let query: Vec<(&str, &str)> = query.split('&').filter_map(|p| p.split_once('=')).collect();
+        let cookie = request
+            .lines()
+            .find_map(|l| {
+                let (name, value) = l.split_once(':')?;
+                name.eq_ignore_ascii_case("cookie")
+                    .then(|| value.trim().to_string())
+            })
+            .unwrap_or_default();
+        state.received_cookies.push(cookie.clone());
+
+        if let Some(status) = state.status {
+            return http_response(status, &[], "<html><body>Server error</body></html>");
+        }
+        if path == LOGIN_PATH {
+            return Self::respond_login(&mut state, request);
+        }
+        if path != MEMBERS_PATH && path != DATATABLE_PATH {
+            return http_response(404, &[], "<html><body>Not found</body></html>");
+        }
+        let authenticated = cookie
+            .split(';')
+            .filter_map(|c| c.trim().split_once('='))
+            .any(|(name, value)| name == "su_session" && value == state.session);
+        if !authenticated && state.login_redirect {
+            return http_response(302, &[format!("Location: {}", LOGIN_PATH)], "");
+        }
+        if !authenticated {
+            return http_response(200, &[], NOT_AUTHENTICATED_PAGE);
+        }
+
+        let mut headers = vec![];
+        if let Some(session) = state.rotate_to.take() {
+            headers.push(format!(
+                "Set-Cookie: su_session={}; path=/; HttpOnly",
+                session
+            ));
+            state.session = session;
+        }
+        let body = if path == DATATABLE_PATH {
+            Self::datatable_json(&state, &query)
+        } else {
+            Self::members_html(&state, &query)
+        };
+        http_response(200, &headers, &body)
+    }
+
+    fn cells(id: u32, name: &str) -> [String; 6] {
+        [
+            id.to_string(),
+            name.to_string(),
+            "Full Membership".to_string(),
+            "01/09/2022 10:30".to_string(),
+            "31/08/2023".to_string(),
+            format!(
+                "<a href=\"mailto:{}@nottingham.ac.uk\">{}@nottingham.ac.uk</a>",
+                id, id
+            ),
+        ]
+    }
+
+    fn members_html(state: &State, query: &[(&str, &str)]) -> String {
+        let param = |name| query.iter().find(|(n, _)| *n == name).map(|(_, v)| *v);
+        let page_size = state.page_size.unwrap_or(usize::MAX).max(1);
+        let page: usize = param("page").and_then(|p| p.parse().ok()).unwrap_or(1);
+        let start = page.saturating_sub(1).saturating_mul(page_size);
+        let members = if state.datatable {
+            &[][..]
+        } else {
+            &state.members[start.min(state.members.len())..]
+        };
+        let rows: String = members
+            .iter()
+            .take(page_size)
+            .map(|(id, name)| {
+                let cells: String = Self::cells(*id, name)
+                    .iter()
+                    .map(|c| format!("<td>{}</td>", c))
+                    .collect();
+                format!("<tr>{}</tr>", cells)
+            })
+            .collect();
+        let mut footer = String::new();
+        if state.page_size.is_some() && !state.datatable {
+            footer = format!(
+                "<div class=\"dataTables_info\">Showing {} to {} of {} entries</div>",
+                start + 1,
+                (start + page_size).min(state.members.len()),
+                state.advertised.unwrap_or(state.members.len())
+            );
+            if start + page_size < state.members.len() {
+                footer.push_str(&format!(
+                    "<a rel=\"next\" href=\"?page={}\">Next</a>",
+                    page + 1
+                ));
+            }
+        }
+        let source = if state.datatable {
+            format!(" data-ajax-url=\"{}\"", DATATABLE_PATH)
+        } else {
+            String::new()
+        };
+        format!(
+            "<html><body><table id=\"group-member-list-datatable\"{}><thead><tr><th>Student ID</th><th>Name</th><th>Type</th><th>Purchased</th><th>Expires</th><th>Email</th></tr></thead><tbody>{}</tbody></table>{}</body></html>",
+            source, rows, footer
+        )
+    }
+
+    fn datatable_json(state: &State, query: &[(&str, &str)]) -> String {
+        let param = |name| {
+            query
+                .iter()
+                .find(|(n, _)| *n == name)
+                .and_then(|(_, v)| v.parse::<usize>().ok())
+        };
+        let start = param("start").unwrap_or(0).min(state.members.len());
+        let length = param("length")
+            .unwrap_or(usize::MAX)
+            .min(state.page_size.unwrap_or(usize::MAX));
+        let data: Vec<_> = state.members[start..]
+            .iter()
+            .take(length)
+            .map(|(id, name)| Self::cells(*id, name))
+            .collect();
+        if state.hide_totals {
+            return serde_json::json!({ "draw": param("draw").unwrap_or(0), "data": data })
+                .to_string();
+        }
+        serde_json::json!({
+            "draw": param("draw").unwrap_or(0),
+            "recordsTotal": state.members.len(),
+            "recordsFiltered": state.advertised.unwrap_or(state.members.len()),
+            "data": data,
+        })
+        .to_string()
+    }
+}
+
+fn http_response(status: u16, headers: &[String], body: &str) -> String {
+    let mut response = format!(
+        "HTTP/1.1 {} Fake\r\nContent-Type: text/html\r\nContent-Length: {}\r\nConnection: close\r\n",
+        status,
+        body.len()
+    );
+    for header in headers {
+        response.push_str(header);
+        response.push_str("\r\n");
+    }
+    response.push_str("\r\n");
+    response.push_str(body);
+    response
+}
@@ -0,0 +1,224 @@
+use anyhow::Result;
+use fallible_iterator::FallibleIterator;
+use poise::serenity_prelude::{GuildInfo, Http};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+
+use crate::committee;
+use crate::config::{parse_members_pages, Config};
+use crate::guild_members;
+use crate::membership_source::SourceKind;
+use crate::type_roles;
+
+/// The settings a guild has changed with `/config`, kept in the `guild_configs` table. Anything
+/// left unset falls back to the environment.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GuildConfig {
+    pub guild_id: u64,
+    pub member_role_id: Option<u64>,
+    pub privileged_role_id: Option<u64>,
+    /// Role names to look for until the roles are picked by ID.
+    pub member_role_name: Option<String>,
+    pub privileged_role_name: Option<String>,
+    /// Members pages in the `MEMBERS_URL` format. Setting these gives the guild its own
+    /// memberships instead of sharing the ones from `MEMBERS_URL`.
+    pub members_url: Option<String>,
+    pub membership_purchase_url: Option<String>,
+    pub committee_channel_id: Option<u64>,
+    pub audit_channel_id: Option<u64>,
+}
+
+const COLUMNS: &str = "guild_id, member_role_name, privileged_role_name, members_url, membership_purchase_url, committee_channel_id, member_role_id, privileged_role_id, audit_channel_id";
+
+impl GuildConfig {
+    /// The stored settings of `guild_id`, or none if it has never been configured.
+    pub fn get(conn: &Connection, guild_id: u64) -> Result<Self> {
+        let config = conn
+            .query_row(
+                &format!("SELECT {} FROM guild_configs WHERE guild_id = ?1", COLUMNS),
+                params![guild_id],
+                Self::from_row,
+            )
+            .optional()?;
+        Ok(config.unwrap_or(Self {
+            guild_id,
+            ..Default::default()
+        }))
+    }
+
+    pub fn get_all(conn: &Connection) -> Result<Vec<Self>> {
+        let mut stmt = conn.prepare(&format!("SELECT {} FROM guild_configs", COLUMNS))?;
+        let rows = stmt.query(params![])?;
+        Ok(rows.map(Self::from_row).collect()?)
+    }
+
+    pub fn save(&self, conn: &Connection) -> Result<()> {
+        conn.execute(
+            &format!(
+                "INSERT OR REPLACE INTO guild_configs ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                COLUMNS
+            ),
+            params![
+                self.guild_id,
+                self.member_role_name,
+                self.privileged_role_name,
+                self.members_url,
+                self.membership_purchase_url,
+                self.committee_channel_id,
+                self.member_role_id,
+                self.privileged_role_id,
+                self.audit_channel_id
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// `config` with these settings in place of the environment's.
+    pub fn apply(&self, config: &Config) -> Result<Config> {
+        let mut config = config.clone();
+        config.guild_id = self.guild_id;
+        if let Some(name) = &self.member_role_name {
+            config.member_role_name = name.clone();
+        }
+        if let Some(name) = &self.privileged_role_name {
+            config.privileged_role_name = name.clone();
+        }
+        config.member_role_id = self.member_role_id;
+        config.privileged_role_id = self.privileged_role_id;
+        if let Some(members_url) = &self.members_url {
+            config.membership_source = SourceKind::Sums;
+            config.members_pages = parse_members_pages(members_url)?;
+            config.scope = self.scope();
+        }
+        if let Some(url) = &self.membership_purchase_url {
+            config.membership_purchase_url = Some(url.clone());
+        }
+        if let Some(channel_id) = self.committee_channel_id {
+            config.committee_channel_id = Some(channel_id);
+        }
+        if let Some(channel_id) = self.audit_channel_id {
+            config.audit_channel_id = Some(channel_id);
+        }
+        Ok(config)
+    }
+
+    /// The scope of the memberships this guild uses, see [`Config::scope`].
+    pub fn scope(&self) -> u64 {
+        match self.members_url {
+            Some(_) => self.guild_id,
+            None => 0,
+        }
+    }
+
+    fn from_row(r: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            guild_id: r.get(0)?,
+            member_role_name: r.get(1)?,
+            privileged_role_name: r.get(2)?,
+            members_url: r.get(3)?,
+            membership_purchase_url: r.get(4)?,
+            committee_channel_id: r.get(5)?,
+            member_role_id: r.get(6)?,
+            privileged_role_id: r.get(7)?,
+            audit_channel_id: r.get(8)?,
+        })
+    }
+}
+
+/// Checks that every guild's member and privileged roles still exist, alerting its committee
+/// channel if not. Roles still found by name are pinned by ID, so renaming them later is safe.
+pub async fn check_roles(config: &Config) -> Result<()> {
+    let http = config.get_http();
+    for guild in guild_members::fetch_guilds(&http).await? {
+        // One guild we can't check shouldn't stop the others being checked
+        if let Err(e) = check_guild_roles(config, &http, &guild).await {
+            log::error!("Failed to check the roles of {}: {:#}", guild.name, e);
+        }
+    }
+    Ok(())
+}
+
+async fn check_guild_roles(config: &Config, http: &Http, guild: &GuildInfo) -> Result<()> {
+    let guild_id = *guild.id.as_u64();
+    let roles = guild.id.roles(http).await?;
+    let guild_config = config.for_guild(guild_id)?;
+    let mut problems = vec![];
+    {
+        let conn = config.get_sqlite_conn()?;
+        let mut stored = GuildConfig::get(&conn, guild_id)?;
+        let pinned = stored.clone();
+        match guild_config.member_role(&roles) {
+            Ok(role) => stored.member_role_id = Some(*role.as_u64()),
+            Err(e) => problems.push(format!("member role: {}", e)),
+        }
+        match guild_config.privileged_role(&roles) {
+            Ok(role) => stored.privileged_role_id = Some(*role.as_u64()),
+            Err(e) => problems.push(format!("privileged role: {}", e)),
+        }
+        if stored != pinned {
+            stored.save(&conn)?;
+            log::info!("Pinned the roles of {} by ID", guild.name);
+        }
+        for missing in type_roles::pin(&conn, &guild_config, guild_id, &roles)? {
+            log::warn!("Type role missing in {}, {}", guild.name, missing);
+        }
+    }
+    if problems.is_empty() {
+        return Ok(());
+    }
+    let alert = format!(
+        ":warning: Some of my roles in {} are missing, so commands there will fail until they're picked again with /setup.\n{}",
+        guild.name,
+        problems.join("\n")
+    );
+    log::error!("{}", alert);
+    if guild_config.committee_channel_id.is_some() {
+        committee::notify(&guild_config, &alert).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::guild_config::GuildConfig;
+    use crate::migrations::migrate;
+
+    #[test]
+    fn apply_overrides() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::test(dir.path());
+        let mut conn = config.get_sqlite_conn().unwrap();
+        migrate(&mut conn).unwrap();
+
+        let unset = GuildConfig::get(&conn, 42).unwrap();
+        assert_eq!(unset.guild_id, 42);
+        let applied = unset.apply(&config).unwrap();
+        assert_eq!(applied.scope, 0);
+        assert!(!applied.sees_all_memberships());
+        assert!(Config {
+            home_guild_id: Some(42),
+            ..applied.clone()
+        }
+        .sees_all_memberships());
+        assert_eq!(applied.member_role_name, "Member");
+        assert_eq!(applied.members_pages, config.members_pages);
+
+        let guild = GuildConfig {
+            guild_id: 42,
+            member_role_name: Some("Paid Up".to_string()),
+            members_url: Some("alumni=https://sums.su/groups/400/members".to_string()),
+            committee_channel_id: Some(7),
+            ..Default::default()
+        };
+        guild.save(&conn).unwrap();
+        assert_eq!(GuildConfig::get(&conn, 42).unwrap(), guild);
+        assert_eq!(GuildConfig::get_all(&conn).unwrap(), vec![guild.clone()]);
+        let applied = guild.apply(&config).unwrap();
+        assert_eq!(applied.scope, 42);
+        assert!(applied.sees_all_memberships());
+        assert_eq!(applied.member_role_name, "Paid Up");
+        assert_eq!(applied.privileged_role_name, "Committee");
+        assert_eq!(applied.members_pages[0].tag.as_deref(), Some("alumni"));
+        assert_eq!(applied.committee_channel_id, Some(7));
+    }
+}
@@ -0,0 +1,42 @@
+use anyhow::Result;
+use poise::serenity_prelude::http::GuildPagination;
+use poise::serenity_prelude::{GuildId, GuildInfo, Http, Member};
+
+/// The most members Discord will return from a single member list request.
+const PAGE_SIZE: u64 = 1000;
+/// The most guilds Discord will return from a single guild list request.
+const GUILD_PAGE_SIZE: u64 = 200;
+
+/// Fetches every member of `guild_id`, paging through the member list so servers with more than
+/// 1000 members are covered.
+pub async fn fetch_all(http: &Http, guild_id: GuildId) -> Result<Vec<Member>> {
+    let mut members = vec![];
+    loop {
+        let after = members.last().map(|m: &Member| m.user.id);
+        let page = guild_id.members(http, Some(PAGE_SIZE), after).await?;
+        let last_page = (page.len() as u64) < PAGE_SIZE;
+        members.extend(page);
+        if last_page {
+            return Ok(members);
+        }
+    }
+}
+
+/// Fetches every guild the bot is in, paging through the guild list so more than one page of
+/// guilds is covered.
+pub async fn fetch_guilds(http: &Http) -> Result<Vec<GuildInfo>> {
+    let mut guilds = vec![];
+    loop {
+        let after = guilds
+            .last()
+            .map(|g: &GuildInfo| GuildPagination::After(g.id));
+        let page = http
+            .get_guilds(after.as_ref(), Some(GUILD_PAGE_SIZE))
+            .await?;
+        let last_page = (page.len() as u64) < GUILD_PAGE_SIZE;
+        guilds.extend(page);
+        if last_page {
+            return Ok(guilds);
+        }
+    }
+}
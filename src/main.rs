@@ -1,13 +1,27 @@
 use crate::config::Config;
-use crate::cookie_database::CookieDatabase;
-use crate::membership::Membership;
 use tokio_schedule::Job;
 
+mod audit_log;
 mod bot;
+mod committee;
 mod config;
+mod cookie_cipher;
 mod cookie_database;
+#[cfg(test)]
+mod fake_sums;
+mod guild_config;
+mod guild_members;
 mod membership;
+mod membership_event;
+mod membership_source;
+mod migrations;
+mod prune;
+mod registration;
+mod scrape_layout;
 mod scraper;
+mod source_health;
+mod stats;
+mod type_roles;
 
 #[tokio::main(flavor = "multi_thread")]
 async fn main() {
@@ -18,24 +32,43 @@ async fn main() {
         std::env::set_var("RUST_LOG", "bruce=info");
     }
     env_logger::init();
-    let config = Config::generate();
-    let conn = match config.get_sqlite_conn() {
+    let mut config = Config::generate();
+    let mut conn = match config.get_sqlite_conn() {
         Ok(client) => client,
         Err(e) => {
             log::error!("{}", e);
             return;
         }
     };
-    Membership::init_table(&conn).expect("initialize membership table");
-    CookieDatabase::init_table(&conn).expect("initialize cookie table");
+    migrations::migrate(&mut conn).expect("migrate database");
+    match registration::home_guild(&config).await {
+        Ok(home_guild_id) => config.home_guild_id = home_guild_id,
+        Err(e) => log::error!("Failed to find the home server: {}", e),
+    }
+    if let Err(e) = registration::adopt_legacy(&config) {
+        log::error!("Failed to move old registrations: {}", e);
+    }
 
-    scraper::init(config.clone())
-        .await
-        .expect("initialize scraper");
+    if let Err(err) = scraper::init(config.clone()).await {
+        log::error!(
+            "Failed to initialize scraper, will keep retrying: {:#}",
+            err
+        );
+    }
+    if let Err(err) = guild_config::check_roles(&config).await {
+        log::error!("Failed to check roles: {}", err);
+    }
     let bot = tokio::spawn(bot::build_framework(config.clone()).run());
-    while let Err(err) =
-        tokio::spawn(tokio_schedule::every(30).minutes().perform(scraper::run)).await
-    {
+    if let Some(schedule) = config.auto_prune_schedule {
+        tokio::spawn(schedule.perform(config.clone()));
+    }
+    let scrape = || {
+        let config = config.clone();
+        tokio_schedule::every(30)
+            .minutes()
+            .perform(move || scraper::run(config.clone()))
+    };
+    while let Err(err) = tokio::spawn(scrape()).await {
         log::error!("{}", err);
     }
     bot.await.expect("bot running").expect("bot running");
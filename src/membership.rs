@@ -1,97 +1,327 @@
 use anyhow::Result;
 use fallible_iterator::FallibleIterator;
-use rusqlite::{params, Connection};
+use rusqlite::{params, Connection, Row};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct Membership {
     pub student_id: u32,
     pub name: String,
+    /// The account linked to the membership in the guild it was loaded for, see [`ANY_GUILD`].
     pub discord_id: Option<u64>,
     pub should_drop: bool,
+    /// Unix timestamp of when `should_drop` was last set.
+    pub dropped_at: Option<i64>,
+    /// The membership product bought on SUMS, e.g. "Full Membership".
+    pub membership_type: Option<String>,
+    /// Unix timestamp of when the membership was bought.
+    pub purchased_at: Option<i64>,
+    /// Unix timestamp of when the membership runs out.
+    pub expires_at: Option<i64>,
+    pub email: Option<String>,
+    /// Tags of the members pages the membership was found on.
+    pub tags: Vec<String>,
+    /// The guild whose members pages the membership was found on, or 0 for `MEMBERS_URL`. See
+    /// [`Config::scope`](crate::config::Config::scope).
+    pub guild_id: u64,
 }
 
+pub fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock after 1970")
+        .as_secs() as i64
+}
+
+/// Memberships are stored once per scope, but guilds sharing a scope each link their own members,
+/// so loaders take the guild to fill in `discord_id` for, or this to have it filled in if the
+/// membership is linked anywhere.
+pub const ANY_GUILD: u64 = 0;
+
+/// The columns of a membership, with the Discord account linked in the guild bound to `?1`.
+const COLUMNS: &str = "student_id, name, (SELECT MIN(r.discord_id) FROM registrations r WHERE r.scope = memberships.guild_id AND r.student_id = memberships.student_id AND (?1 = 0 OR r.guild_id = ?1)), should_drop, dropped_at, membership_type, purchased_at, expires_at, email, tags, guild_id";
+
 impl Membership {
-    pub fn init_table(conn: &Connection) -> Result<()> {
-        conn.execute("CREATE TABLE IF NOT EXISTS memberships (student_id INT NOT NULL PRIMARY KEY, name VARCHAR NOT NULL, discord_id BIGINT, should_drop BIT NOT NULL)", params![])?;
-        Ok(())
+    pub fn get_by_student_id(
+        conn: &Connection,
+        scope: u64,
+        guild_id: u64,
+        student_id: u32,
+    ) -> Result<Self> {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM memberships WHERE guild_id = ?2 AND student_id = ?3",
+            COLUMNS
+        ))?;
+        Ok(stmt.query_row(params![guild_id, scope, student_id], Self::from_row)?)
     }
 
-    pub fn get_by_student_id(conn: &Connection, student_id: u32) -> Result<Self> {
-        let mut stmt = conn.prepare(
-            "SELECT name, discord_id, should_drop FROM memberships WHERE student_id = ?1",
-        )?;
-        Ok(stmt.query_row(params![student_id], |r| {
-            Ok(Self {
-                student_id,
-                name: r.get(0)?,
-                discord_id: r.get(1)?,
-                should_drop: r.get(2)?,
-            })
-        })?)
+    /// The membership `discord_id` is linked to in `guild_id`.
+    pub fn get_by_discord_id(
+        conn: &Connection,
+        scope: u64,
+        guild_id: u64,
+        discord_id: u64,
+    ) -> Result<Self> {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM memberships WHERE guild_id = ?2 AND student_id IN (SELECT student_id FROM registrations WHERE guild_id = ?1 AND scope = ?2 AND discord_id = ?3)",
+            COLUMNS
+        ))?;
+        Ok(stmt.query_row(params![guild_id, scope, discord_id], Self::from_row)?)
     }
 
-    pub fn get_by_discord_id(conn: &Connection, discord_id: u64) -> Result<Self> {
-        let mut stmt = conn.prepare(
-            "SELECT student_id, name, should_drop FROM memberships WHERE discord_id = ?1",
-        )?;
-        Ok(stmt.query_row(params![discord_id], |r| {
-            Ok(Self {
-                student_id: r.get(0)?,
-                name: r.get(1)?,
-                discord_id: Some(discord_id),
-                should_drop: r.get(2)?,
-            })
-        })?)
-    }
-
-    pub fn get_all(conn: &Connection) -> Result<Vec<Self>> {
-        let mut stmt =
-            conn.prepare("SELECT student_id, name, discord_id, should_drop FROM memberships")?;
+    pub fn get_all(conn: &Connection, scope: u64, guild_id: u64) -> Result<Vec<Self>> {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM memberships WHERE guild_id = ?2",
+            COLUMNS
+        ))?;
         Ok(stmt
-            .query(params![])
+            .query(params![guild_id, scope])
             .expect("get all memberships")
-            .map(|r| {
-                Ok(Self {
-                    student_id: r.get(0)?,
-                    name: r.get(1)?,
-                    discord_id: r.get(2)?,
-                    should_drop: r.get(3)?,
-                })
-            })
+            .map(Self::from_row)
             .collect()?)
     }
 
-    pub fn update_disord_id(&mut self, conn: &Connection, discord_id: Option<u64>) -> Result<()> {
+    fn from_row(r: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            student_id: r.get(0)?,
+            name: r.get(1)?,
+            discord_id: r.get(2)?,
+            should_drop: r.get(3)?,
+            dropped_at: r.get(4)?,
+            membership_type: r.get(5)?,
+            purchased_at: r.get(6)?,
+            expires_at: r.get(7)?,
+            email: r.get(8)?,
+            tags: r
+                .get::<_, String>(9)?
+                .split(',')
+                .filter(|tag| !tag.is_empty())
+                .map(str::to_string)
+                .collect(),
+            guild_id: r.get(10)?,
+        })
+    }
+
+    /// Links `discord_id` to this membership in `guild_id`.
+    pub fn link(&mut self, conn: &Connection, guild_id: u64, discord_id: u64) -> Result<()> {
         conn.execute(
-            "UPDATE memberships SET discord_id = ?1 WHERE student_id = ?2",
-            params![discord_id, self.student_id],
+            "INSERT INTO registrations (guild_id, discord_id, scope, student_id) VALUES (?1, ?2, ?3, ?4)",
+            params![guild_id, discord_id, self.guild_id, self.student_id],
         )?;
-        self.discord_id = discord_id;
+        self.discord_id = Some(discord_id);
         Ok(())
     }
 
+    /// Removes the link to this membership made in `guild_id`, leaving other guilds' alone.
+    pub fn unlink(&mut self, conn: &Connection, guild_id: u64) -> Result<()> {
+        conn.execute(
+            "DELETE FROM registrations WHERE guild_id = ?1 AND scope = ?2 AND student_id = ?3",
+            params![guild_id, self.guild_id, self.student_id],
+        )?;
+        self.discord_id = None;
+        Ok(())
+    }
+
+    /// How many memberships of `scope` have been linked in `guild_id` since the unix timestamp
+    /// `since` and are still linked.
+    pub fn count_linked_since(
+        conn: &Connection,
+        scope: u64,
+        guild_id: u64,
+        since: i64,
+    ) -> Result<usize> {
+        Ok(conn.query_row(
+            "SELECT COUNT(*) FROM registrations WHERE guild_id = ?1 AND scope = ?2 AND created_at >= ?3",
+            params![guild_id, scope, since],
+            |r| r.get(0),
+        )?)
+    }
+
+    /// Whether this membership is still linked in any guild.
+    pub fn is_linked(&self, conn: &Connection) -> Result<bool> {
+        Ok(conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM registrations WHERE scope = ?1 AND student_id = ?2)",
+            params![self.guild_id, self.student_id],
+            |r| r.get(0),
+        )?)
+    }
+
     pub fn update_should_drop(&mut self, conn: &Connection, should_drop: bool) -> Result<()> {
+        let dropped_at = should_drop.then(unix_now);
         conn.execute(
-            "UPDATE memberships SET should_drop = ?1 WHERE student_id = ?2",
-            params![should_drop, self.student_id],
+            "UPDATE memberships SET should_drop = ?1, dropped_at = ?2 WHERE guild_id = ?3 AND student_id = ?4",
+            params![should_drop, dropped_at, self.guild_id, self.student_id],
         )?;
         self.should_drop = should_drop;
+        self.dropped_at = dropped_at;
         Ok(())
     }
 
+    /// Updates the name and SUMS details to match `scraped`.
+    pub fn update_details(&mut self, conn: &Connection, scraped: &Membership) -> Result<()> {
+        conn.execute(
+            "UPDATE memberships SET name = ?1, membership_type = ?2, purchased_at = ?3, expires_at = ?4, email = ?5, tags = ?6 WHERE guild_id = ?7 AND student_id = ?8",
+            params![
+                scraped.name,
+                scraped.membership_type,
+                scraped.purchased_at,
+                scraped.expires_at,
+                scraped.email,
+                scraped.tags.join(","),
+                self.guild_id,
+                self.student_id
+            ],
+        )?;
+        self.name = scraped.name.clone();
+        self.membership_type = scraped.membership_type.clone();
+        self.purchased_at = scraped.purchased_at;
+        self.expires_at = scraped.expires_at;
+        self.email = scraped.email.clone();
+        self.tags = scraped.tags.clone();
+        Ok(())
+    }
+
+    /// Whether `scraped` has a different name or SUMS details to this membership.
+    pub fn details_differ(&self, scraped: &Membership) -> bool {
+        self.name != scraped.name
+            || self.membership_type != scraped.membership_type
+            || self.purchased_at != scraped.purchased_at
+            || self.expires_at != scraped.expires_at
+            || self.email != scraped.email
+            || self.tags != scraped.tags
+    }
+
+    /// Up to `limit` memberships whose name contains `query`, ignoring case, sorted by name. With
+    /// `linked_only`, only memberships linked in `guild_id` are searched.
+    pub fn search_by_name(
+        conn: &Connection,
+        scope: u64,
+        guild_id: u64,
+        query: &str,
+        limit: usize,
+        linked_only: bool,
+    ) -> Result<Vec<Self>> {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM memberships WHERE guild_id = ?2 AND instr(lower(name), lower(?3)) > 0 AND (NOT ?5 OR student_id IN (SELECT student_id FROM registrations WHERE guild_id = ?1 AND scope = ?2)) ORDER BY name LIMIT ?4",
+            COLUMNS
+        ))?;
+        let rows = stmt.query(params![guild_id, scope, query.trim(), limit, linked_only])?;
+        Ok(rows.map(Self::from_row).collect()?)
+    }
+
     pub fn insert(&self, conn: &Connection) -> Result<()> {
         conn.execute(
-            "INSERT OR IGNORE INTO memberships (student_id, name, should_drop) VALUES (?1, ?2, 0)",
-            params![self.student_id, self.name],
+            "INSERT OR IGNORE INTO memberships (student_id, name, should_drop, membership_type, purchased_at, expires_at, email, tags, guild_id) VALUES (?1, ?2, 0, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                self.student_id,
+                self.name,
+                self.membership_type,
+                self.purchased_at,
+                self.expires_at,
+                self.email,
+                self.tags.join(","),
+                self.guild_id
+            ],
         )?;
         Ok(())
     }
 
-    pub fn delete(self, conn: &Connection) -> Result<()> {
+    pub fn delete(&self, conn: &Connection) -> Result<()> {
         conn.execute(
-            "DELETE FROM memberships WHERE student_id = ?1",
-            params![self.student_id],
+            "DELETE FROM memberships WHERE guild_id = ?1 AND student_id = ?2",
+            params![self.guild_id, self.student_id],
         )?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::membership::{Membership, ANY_GUILD};
+    use crate::migrations::migrate;
+    use rusqlite::Connection;
+
+    #[test]
+    fn search_by_name() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        for (guild_id, student_id, name) in [
+            (0, 1, "Ada Lovelace"),
+            (0, 2, "Alan Turing"),
+            (0, 3, "Grace Hopper"),
+            (5, 4, "Alan Kay"),
+        ] {
+            Membership {
+                student_id,
+                name: name.to_string(),
+                guild_id,
+                ..Default::default()
+            }
+            .insert(&conn)
+            .unwrap();
+        }
+
+        Membership::get_by_student_id(&conn, 0, 10, 2)
+            .unwrap()
+            .link(&conn, 10, 42)
+            .unwrap();
+
+        let ids = |query: &str, limit: usize| -> Vec<u32> {
+            Membership::search_by_name(&conn, 0, ANY_GUILD, query, limit, false)
+                .unwrap()
+                .iter()
+                .map(|m| m.student_id)
+                .collect()
+        };
+        assert_eq!(ids("alan", 10), vec![2]);
+        assert_eq!(ids(" a", 10), vec![1, 2, 3]);
+        assert_eq!(ids("a", 2), vec![1, 2]);
+        assert_eq!(ids("%", 10), Vec::<u32>::new());
+        let linked = Membership::search_by_name(&conn, 0, 10, "a", 10, true).unwrap();
+        assert_eq!(
+            linked
+                .iter()
+                .map(|m| (m.student_id, m.discord_id))
+                .collect::<Vec<_>>(),
+            vec![(2, Some(42))]
+        );
+    }
+
+    #[test]
+    fn links_per_guild() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        Membership {
+            student_id: 1,
+            name: "Ada Lovelace".to_string(),
+            ..Default::default()
+        }
+        .insert(&conn)
+        .unwrap();
+        let linked = |guild_id: u64| {
+            Membership::get_by_student_id(&conn, 0, guild_id, 1)
+                .unwrap()
+                .discord_id
+        };
+
+        let mut membership = Membership::get_by_student_id(&conn, 0, 10, 1).unwrap();
+        membership.link(&conn, 10, 42).unwrap();
+        Membership::get_by_student_id(&conn, 0, 20, 1)
+            .unwrap()
+            .link(&conn, 20, 43)
+            .unwrap();
+        assert_eq!(
+            (linked(10), linked(20), linked(30)),
+            (Some(42), Some(43), None)
+        );
+        assert_eq!(linked(ANY_GUILD), Some(42));
+        assert!(Membership::get_by_discord_id(&conn, 0, 10, 42).is_ok());
+        assert!(Membership::get_by_discord_id(&conn, 0, 20, 42).is_err());
+
+        membership.unlink(&conn, 10).unwrap();
+        assert_eq!((linked(10), linked(20)), (None, Some(43)));
+        assert!(membership.is_linked(&conn).unwrap());
+        membership.unlink(&conn, 20).unwrap();
+        assert!(!membership.is_linked(&conn).unwrap());
+    }
+}
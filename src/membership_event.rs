@@ -0,0 +1,174 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error, Result};
+use fallible_iterator::FallibleIterator;
+use rusqlite::types::Type;
+use rusqlite::{params, Connection, Row};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    Joined,
+    Renewed,
+    Lapsed,
+    Registered,
+    Unregistered,
+    Pruned,
+}
+
+impl EventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::Joined => "joined",
+            EventKind::Renewed => "renewed",
+            EventKind::Lapsed => "lapsed",
+            EventKind::Registered => "registered",
+            EventKind::Unregistered => "unregistered",
+            EventKind::Pruned => "pruned",
+        }
+    }
+}
+
+impl Display for EventKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for EventKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "joined" => EventKind::Joined,
+            "renewed" => EventKind::Renewed,
+            "lapsed" => EventKind::Lapsed,
+            "registered" => EventKind::Registered,
+            "unregistered" => EventKind::Unregistered,
+            "pruned" => EventKind::Pruned,
+            _ => return Err(anyhow!("Unknown membership event kind {}", s)),
+        })
+    }
+}
+
+/// An entry in the append-only `membership_events` table.
+#[derive(Debug)]
+pub struct MembershipEvent {
+    pub student_id: u32,
+    pub discord_id: Option<u64>,
+    pub kind: EventKind,
+    /// The Discord user who caused the event, or `None` for the scraper.
+    pub actor_id: Option<u64>,
+    /// Unix timestamp in seconds.
+    pub created_at: i64,
+}
+
+impl MembershipEvent {
+    pub fn record(
+        conn: &Connection,
+        guild_id: u64,
+        student_id: u32,
+        discord_id: Option<u64>,
+        kind: EventKind,
+        actor_id: Option<u64>,
+    ) -> Result<()> {
+        conn.execute(
+            "INSERT INTO membership_events (guild_id, student_id, discord_id, kind, actor_id) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![guild_id, student_id, discord_id, kind.as_str(), actor_id],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_by_student_id(
+        conn: &Connection,
+        guild_id: u64,
+        student_id: u32,
+    ) -> Result<Vec<Self>> {
+        let mut stmt = conn.prepare(
+            "SELECT student_id, discord_id, kind, actor_id, created_at FROM membership_events WHERE guild_id = ?1 AND student_id = ?2 ORDER BY id",
+        )?;
+        let rows = stmt.query(params![guild_id, student_id])?;
+        Ok(rows.map(Self::from_row).collect()?)
+    }
+
+    pub fn get_by_discord_id(
+        conn: &Connection,
+        guild_id: u64,
+        discord_id: u64,
+    ) -> Result<Vec<Self>> {
+        let mut stmt = conn.prepare(
+            "SELECT student_id, discord_id, kind, actor_id, created_at FROM membership_events WHERE guild_id = ?1 AND discord_id = ?2 ORDER BY id",
+        )?;
+        let rows = stmt.query(params![guild_id, discord_id])?;
+        Ok(rows.map(Self::from_row).collect()?)
+    }
+
+    /// How many `kind` events `guild_id` has had since the unix timestamp `since`.
+    pub fn count_since(
+        conn: &Connection,
+        guild_id: u64,
+        kind: EventKind,
+        since: i64,
+    ) -> Result<usize> {
+        Ok(conn.query_row(
+            "SELECT COUNT(*) FROM membership_events WHERE guild_id = ?1 AND kind = ?2 AND created_at >= ?3",
+            params![guild_id, kind.as_str(), since],
+            |r| r.get(0),
+        )?)
+    }
+
+    fn from_row(r: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            student_id: r.get(0)?,
+            discord_id: r.get(1)?,
+            kind: r.get::<_, String>(2)?.parse().map_err(|e: Error| {
+                rusqlite::Error::FromSqlConversionFailure(2, Type::Text, e.into())
+            })?,
+            actor_id: r.get(3)?,
+            created_at: r.get(4)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::membership_event::{EventKind, MembershipEvent};
+    use crate::migrations::migrate;
+    use rusqlite::Connection;
+
+    #[test]
+    fn record_and_query() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        MembershipEvent::record(&conn, 0, 12345678, None, EventKind::Joined, None).unwrap();
+        MembershipEvent::record(&conn, 0, 12345678, Some(42), EventKind::Registered, Some(7))
+            .unwrap();
+        MembershipEvent::record(&conn, 0, 87654321, None, EventKind::Joined, None).unwrap();
+        MembershipEvent::record(&conn, 5, 12345678, None, EventKind::Joined, None).unwrap();
+
+        let events = MembershipEvent::get_by_student_id(&conn, 0, 12345678).unwrap();
+        assert_eq!(
+            events.iter().map(|e| e.kind).collect::<Vec<_>>(),
+            vec![EventKind::Joined, EventKind::Registered]
+        );
+        assert!(events.iter().all(|e| e.created_at > 0));
+
+        let events = MembershipEvent::get_by_discord_id(&conn, 0, 42).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].actor_id, Some(7));
+        assert_eq!(
+            MembershipEvent::get_by_student_id(&conn, 5, 12345678)
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(
+            MembershipEvent::count_since(&conn, 0, EventKind::Joined, 0).unwrap(),
+            2
+        );
+        assert_eq!(
+            MembershipEvent::count_since(&conn, 0, EventKind::Registered, i64::MAX).unwrap(),
+            0
+        );
+    }
+}
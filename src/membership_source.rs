@@ -0,0 +1,154 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context, Error, Result};
+use async_trait::async_trait;
+
+use crate::config::Config;
+use crate::membership::Membership;
+use crate::scraper::SumsSource;
+
+/// Anything that can produce the current list of society memberships.
+///
+/// `scraper::run` reconciles the `memberships` table against the union of what the sources return,
+/// so an implementation only needs to know how to fetch the list, not how it is stored.
+#[async_trait]
+pub trait MembershipSource: Send + Sync {
+    /// A stable name for the source, used to track its health.
+    fn name(&self) -> String;
+
+    async fn fetch(&self) -> Result<Vec<Membership>>;
+}
+
+#[async_trait]
+impl<T: MembershipSource + ?Sized> MembershipSource for Box<T> {
+    fn name(&self) -> String {
+        (**self).name()
+    }
+
+    async fn fetch(&self) -> Result<Vec<Membership>> {
+        (**self).fetch().await
+    }
+}
+
+/// Where memberships come from, picked with `MEMBERSHIP_SOURCE`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum SourceKind {
+    /// The SUMS members pages in `MEMBERS_URL`.
+    #[default]
+    Sums,
+    /// A CSV file of `student_id,name` lines, parsed from `csv:<path>`.
+    Csv(PathBuf),
+}
+
+impl FromStr for SourceKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().split_once(':') {
+            None if s.trim() == "sums" => Ok(Self::Sums),
+            Some(("csv", path)) if !path.is_empty() => Ok(Self::Csv(PathBuf::from(path))),
+            _ => Err(anyhow!(
+                "Unknown membership source {}, expected sums or csv:<path>",
+                s
+            )),
+        }
+    }
+}
+
+/// The sources `config` reads its memberships from.
+pub fn sources(config: &Config) -> Result<Vec<Box<dyn MembershipSource>>> {
+    Ok(match &config.membership_source {
+        SourceKind::Sums => SumsSource::all(config)?
+            .into_iter()
+            .map(|source| Box::new(source) as Box<dyn MembershipSource>)
+            .collect(),
+        SourceKind::Csv(path) => vec![Box::new(CsvSource { path: path.clone() })],
+    })
+}
+
+/// Reads memberships from a CSV file of `student_id,name` lines, for societies whose members
+/// list comes from somewhere other than SUMS.
+pub struct CsvSource {
+    path: PathBuf,
+}
+
+#[async_trait]
+impl MembershipSource for CsvSource {
+    fn name(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    async fn fetch(&self) -> Result<Vec<Membership>> {
+        let contents = std::fs::read_to_string(&self.path)
+            .with_context(|| format!("Failed to read {}", self.path.display()))?;
+        let memberships = contents
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| {
+                let (student_id, name) = line
+                    .split_once(',')
+                    .ok_or_else(|| anyhow!("Line {} has no name", i + 1))?;
+                Ok(Membership {
+                    student_id: student_id
+                        .trim()
+                        .parse()
+                        .with_context(|| format!("Line {} has an invalid student ID", i + 1))?,
+                    name: name.trim().to_string(),
+                    ..Default::default()
+                })
+            })
+            .collect::<Result<Vec<Membership>>>()
+            .with_context(|| format!("Failed to parse {}", self.path.display()))?;
+        // An empty file is more likely a failed export than a society with no members
+        if memberships.is_empty() {
+            return Err(anyhow!("{} has no memberships", self.path.display()));
+        }
+        Ok(memberships)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::membership_source::{sources, SourceKind};
+    use std::path::PathBuf;
+
+    #[test]
+    fn parse_kind() {
+        assert_eq!("sums".parse::<SourceKind>().unwrap(), SourceKind::Sums);
+        assert_eq!(
+            "csv:/data/members.csv".parse::<SourceKind>().unwrap(),
+            SourceKind::Csv(PathBuf::from("/data/members.csv"))
+        );
+        assert!("csv:".parse::<SourceKind>().is_err());
+        assert!("json".parse::<SourceKind>().is_err());
+    }
+
+    #[tokio::test]
+    async fn csv_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("members.csv");
+        let config = Config {
+            membership_source: SourceKind::Csv(path.clone()),
+            ..Config::test(dir.path())
+        };
+        let source = &sources(&config).unwrap()[0];
+
+        std::fs::write(&path, "20123456, Alice Smith\n\n20654321,Bob Jones\n").unwrap();
+        let memberships = source.fetch().await.unwrap();
+        assert_eq!(
+            memberships
+                .iter()
+                .map(|m| (m.student_id, m.name.as_str()))
+                .collect::<Vec<_>>(),
+            vec![(20123456, "Alice Smith"), (20654321, "Bob Jones")]
+        );
+
+        std::fs::write(&path, "20123456,Alice Smith\nnot a line\n").unwrap();
+        assert!(source.fetch().await.is_err());
+        std::fs::write(&path, "").unwrap();
+        assert!(source.fetch().await.is_err());
+    }
+}
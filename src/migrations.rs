@@ -0,0 +1,176 @@
+use anyhow::{anyhow, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+
+/// Schema changes, applied in order. A migration's version is its position in this list plus one,
+/// so new steps must only ever be appended.
+const MIGRATIONS: &[&str] = &[
+    // 1: the tables as they were created before migrations existed
+    "CREATE TABLE IF NOT EXISTS memberships (student_id INT NOT NULL PRIMARY KEY, name VARCHAR NOT NULL, discord_id BIGINT, should_drop BIT NOT NULL);
+     CREATE TABLE IF NOT EXISTS cookies (url VARCHAR NOT NULL PRIMARY KEY, name VARCHAR NOT NULL, value VARCHAR NOT NULL);",
+    // 2: membership history
+    "CREATE TABLE membership_events (id INTEGER PRIMARY KEY AUTOINCREMENT, student_id INT NOT NULL, discord_id BIGINT, kind VARCHAR NOT NULL, actor_id BIGINT, created_at BIGINT NOT NULL DEFAULT (strftime('%s', 'now')));
+     CREATE INDEX membership_events_student_id ON membership_events (student_id);
+     CREATE INDEX membership_events_discord_id ON membership_events (discord_id);",
+    // 3: track when a membership was flagged, for the automatic prune grace period
+    "ALTER TABLE memberships ADD COLUMN dropped_at BIGINT;
+     UPDATE memberships SET dropped_at = strftime('%s', 'now') WHERE should_drop = 1;",
+    // 4: RFC 6265 cookie storage, keeping saved sessions as host-only cookies for their URL's host
+    "ALTER TABLE cookies RENAME TO cookies_by_url;
+     CREATE TABLE cookies (domain VARCHAR NOT NULL, path VARCHAR NOT NULL, name VARCHAR NOT NULL, value VARCHAR NOT NULL, host_only BIT NOT NULL, secure BIT NOT NULL, http_only BIT NOT NULL, expires BIGINT, PRIMARY KEY (domain, path, name));
+     INSERT OR REPLACE INTO cookies (domain, path, name, value, host_only, secure, http_only)
+         SELECT lower(substr(host, 1, instr(host || ':', ':') - 1)), '/', name, value, 1, 0, 1
+         FROM (SELECT substr(rest, 1, instr(rest || '/', '/') - 1) AS host, name, value
+               FROM (SELECT substr(url, instr(url, '://') + 3) AS rest, name, value FROM cookies_by_url));
+     DROP TABLE cookies_by_url;",
+    // 5: membership source health, for session expiry alerts
+    "CREATE TABLE source_health (source VARCHAR NOT NULL PRIMARY KEY, healthy BIT NOT NULL, last_error VARCHAR, last_success BIGINT, unhealthy_since BIGINT, last_alerted BIGINT);",
+    // 6: extra columns from the SUMS members table
+    "ALTER TABLE memberships ADD COLUMN membership_type VARCHAR;
+     ALTER TABLE memberships ADD COLUMN purchased_at BIGINT;
+     ALTER TABLE memberships ADD COLUMN expires_at BIGINT;
+     ALTER TABLE memberships ADD COLUMN email VARCHAR;",
+    // 7: tags of the members pages a membership was found on, comma separated
+    "ALTER TABLE memberships ADD COLUMN tags VARCHAR NOT NULL DEFAULT '';",
+    // 8: per-guild settings, and memberships scoped by guild. Existing rows belong to the
+    // `MEMBERS_URL` scope, 0
+    "CREATE TABLE guild_configs (guild_id BIGINT NOT NULL PRIMARY KEY, member_role_name VARCHAR, privileged_role_name VARCHAR, members_url VARCHAR, membership_purchase_url VARCHAR, committee_channel_id BIGINT);
+     CREATE TABLE memberships_by_guild (guild_id BIGINT NOT NULL DEFAULT 0, student_id INT NOT NULL, name VARCHAR NOT NULL, discord_id BIGINT, should_drop BIT NOT NULL, dropped_at BIGINT, membership_type VARCHAR, purchased_at BIGINT, expires_at BIGINT, email VARCHAR, tags VARCHAR NOT NULL DEFAULT '', PRIMARY KEY (guild_id, student_id));
+     INSERT INTO memberships_by_guild (student_id, name, discord_id, should_drop, dropped_at, membership_type, purchased_at, expires_at, email, tags)
+         SELECT student_id, name, discord_id, should_drop, dropped_at, membership_type, purchased_at, expires_at, email, tags FROM memberships;
+     DROP TABLE memberships;
+     ALTER TABLE memberships_by_guild RENAME TO memberships;
+     ALTER TABLE membership_events ADD COLUMN guild_id BIGINT NOT NULL DEFAULT 0;",
+    // 9: roles picked by ID, so renaming them in Discord doesn't break anything
+    "ALTER TABLE guild_configs ADD COLUMN member_role_id BIGINT;
+     ALTER TABLE guild_configs ADD COLUMN privileged_role_id BIGINT;",
+    // 10: per-guild audit log channel
+    "ALTER TABLE guild_configs ADD COLUMN audit_channel_id BIGINT;",
+    // 11: `MEMBERSHIP_TYPE_ROLES` roles pinned by ID in each guild once found by name
+    "CREATE TABLE type_role_ids (guild_id BIGINT NOT NULL, role_name VARCHAR NOT NULL, role_id BIGINT NOT NULL, PRIMARY KEY (guild_id, role_name));",
+    // 12: Discord links kept per guild, as guilds sharing the `MEMBERS_URL` memberships each
+    // register their own members. Older links to the shared memberships go to guild 0 until
+    // `registration::adopt_legacy` works out which guild made them
+    "CREATE TABLE registrations (guild_id BIGINT NOT NULL, discord_id BIGINT NOT NULL, scope BIGINT NOT NULL, student_id INT NOT NULL, created_at BIGINT NOT NULL DEFAULT (strftime('%s', 'now')), PRIMARY KEY (guild_id, discord_id), UNIQUE (guild_id, scope, student_id));
+     INSERT OR IGNORE INTO registrations (guild_id, discord_id, scope, student_id, created_at)
+         SELECT guild_id, discord_id, guild_id, student_id, COALESCE((SELECT MAX(created_at) FROM membership_events e WHERE e.guild_id = memberships.guild_id AND e.student_id = memberships.student_id AND e.kind = 'registered'), 0)
+         FROM memberships WHERE discord_id IS NOT NULL;
+     ALTER TABLE memberships DROP COLUMN discord_id;",
+];
+
+pub fn latest_version() -> u32 {
+    MIGRATIONS.len() as u32
+}
+
+pub fn current_version(conn: &Connection) -> Result<u32> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (version INT NOT NULL)",
+        params![],
+    )?;
+    Ok(conn
+        .query_row("SELECT version FROM schema_version", params![], |r| {
+            r.get(0)
+        })
+        .optional()?
+        .unwrap_or(0))
+}
+
+/// Brings the database up to the latest schema, running each outstanding migration in its own
+/// transaction alongside the version bump.
+pub fn migrate(conn: &mut Connection) -> Result<()> {
+    let mut version = current_version(conn)?;
+    if version > latest_version() {
+        return Err(anyhow!(
+            "Database schema version {} is newer than this build supports ({})",
+            version,
+            latest_version()
+        ));
+    }
+    for (i, sql) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(sql)?;
+        tx.execute("DELETE FROM schema_version", params![])?;
+        tx.execute(
+            "INSERT INTO schema_version (version) VALUES (?1)",
+            params![i as u32 + 1],
+        )?;
+        tx.commit()?;
+        version = i as u32 + 1;
+        log::info!("Migrated database to schema version {}", version);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::migrations::{current_version, latest_version, migrate};
+    use rusqlite::{params, Connection};
+
+    #[test]
+    fn fresh_database() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        assert_eq!(current_version(&conn).unwrap(), 0);
+        migrate(&mut conn).unwrap();
+        assert_eq!(current_version(&conn).unwrap(), latest_version());
+        migrate(&mut conn).unwrap();
+        assert_eq!(current_version(&conn).unwrap(), latest_version());
+    }
+
+    #[test]
+    fn existing_deployment() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE memberships (student_id INT NOT NULL PRIMARY KEY, name VARCHAR NOT NULL, discord_id BIGINT, should_drop BIT NOT NULL);
+             CREATE TABLE cookies (url VARCHAR NOT NULL PRIMARY KEY, name VARCHAR NOT NULL, value VARCHAR NOT NULL);
+             INSERT INTO memberships (student_id, name, discord_id, should_drop) VALUES (12345678, 'Alice Smith', 42, 0);
+             INSERT INTO cookies (url, name, value) VALUES ('https://Student-Dashboard.sums.su:443/groups/336/members', 'su_session', 'abc123');",
+        )
+        .unwrap();
+        migrate(&mut conn).unwrap();
+        assert_eq!(current_version(&conn).unwrap(), latest_version());
+        let (name, guild_id): (String, u64) = conn
+            .query_row(
+                "SELECT name, guild_id FROM memberships WHERE student_id = 12345678",
+                params![],
+                |r| Ok((r.get(0)?, r.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(name, "Alice Smith");
+        assert_eq!(guild_id, 0);
+        let registration: (u64, u64, u32) = conn
+            .query_row(
+                "SELECT guild_id, discord_id, student_id FROM registrations",
+                params![],
+                |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(registration, (0, 42, 12345678));
+        let cookie: (String, String, String, String) = conn
+            .query_row(
+                "SELECT domain, path, name, value FROM cookies",
+                params![],
+                |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)),
+            )
+            .unwrap();
+        assert_eq!(
+            cookie,
+            (
+                "student-dashboard.sums.su".to_string(),
+                "/".to_string(),
+                "su_session".to_string(),
+                "abc123".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn newer_database() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        conn.execute(
+            "UPDATE schema_version SET version = ?1",
+            params![latest_version() + 1],
+        )
+        .unwrap();
+        assert!(migrate(&mut conn).is_err());
+    }
+}
@@ -0,0 +1,450 @@
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, Error, Result};
+use chrono::Weekday;
+use poise::serenity_prelude::{ChannelId, GuildId, Http, Member, RoleId};
+use tokio_schedule::Job;
+
+use crate::audit_log::{self, AuditEntry};
+use crate::config::Config;
+use crate::guild_members;
+use crate::membership::{unix_now, Membership};
+use crate::membership_event::{EventKind, MembershipEvent};
+
+const PRUNE_BATCH_SIZE: usize = 10;
+const PRUNE_BATCH_PAUSE: Duration = Duration::from_secs(1);
+
+/// When the automatic prune runs, parsed from `AUTO_PRUNE_SCHEDULE`.
+///
+/// Accepts `every <n> hours`, `daily <hh:mm>` or `weekly <weekday> <hh:mm>`, in local time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PruneSchedule {
+    Hours(u32),
+    Daily(u32, u32),
+    Weekly(Weekday, u32, u32),
+}
+
+impl PruneSchedule {
+    /// Runs `run_scheduled` on this schedule forever.
+    pub async fn perform(self, config: Config) {
+        let job = move || run_scheduled(config.clone());
+        match self {
+            PruneSchedule::Hours(hours) => tokio_schedule::every(hours).hours().perform(job),
+            PruneSchedule::Daily(hour, minute) => tokio_schedule::every(1)
+                .day()
+                .at(hour, minute, 0)
+                .perform(job),
+            PruneSchedule::Weekly(weekday, hour, minute) => tokio_schedule::every(1)
+                .week()
+                .on(weekday)
+                .at(hour, minute, 0)
+                .perform(job),
+        }
+        .await
+    }
+}
+
+impl FromStr for PruneSchedule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        fn time(s: &str) -> Result<(u32, u32)> {
+            let (hour, minute) = s
+                .split_once(':')
+                .ok_or_else(|| anyhow!("Expected a time like 03:00, got {}", s))?;
+            let (hour, minute) = (hour.parse()?, minute.parse()?);
+            if hour > 23 || minute > 59 {
+                return Err(anyhow!("{} is not a valid time", s));
+            }
+            Ok((hour, minute))
+        }
+
+        let parts: Vec<&str> = s.split_whitespace().collect();
+        match parts.as_slice() {
+            ["every", hours, "hours" | "hour"] => match hours.parse()? {
+                0 => Err(anyhow!("Prune schedule can't run every 0 hours")),
+                hours => Ok(PruneSchedule::Hours(hours)),
+            },
+            ["daily", at] => {
+                let (hour, minute) = time(at)?;
+                Ok(PruneSchedule::Daily(hour, minute))
+            }
+            ["weekly", weekday, at] => {
+                let weekday = weekday
+                    .parse()
+                    .map_err(|_| anyhow!("{} is not a weekday", weekday))?;
+                let (hour, minute) = time(at)?;
+                Ok(PruneSchedule::Weekly(weekday, hour, minute))
+            }
+            _ => Err(anyhow!(
+                "Unrecognised prune schedule {}, expected `every <n> hours`, `daily <hh:mm>` or `weekly <weekday> <hh:mm>`",
+                s
+            )),
+        }
+    }
+}
+
+/// A member who will lose the member role, and the membership that was linked to them, if any.
+pub struct PruneTarget {
+    pub member: Member,
+    pub membership: Option<Membership>,
+}
+
+impl PruneTarget {
+    pub fn reason(&self) -> String {
+        match &self.membership {
+            Some(membership) => format!("membership {} has lapsed", membership.student_id),
+            None => "not registered to a membership".to_string(),
+        }
+    }
+}
+
+/// The members who will lose the member role and the memberships that will be deleted.
+pub struct PrunePlan {
+    pub member_role: RoleId,
+    /// Membership type roles, also taken away from pruned members.
+    pub type_roles: Vec<RoleId>,
+    pub targets: Vec<PruneTarget>,
+    pub memberships: Vec<Membership>,
+}
+
+/// What happened when a [`PrunePlan`] was executed.
+pub struct PruneOutcome {
+    pub pruned: usize,
+    /// The memberships unlinked along the way. Each is deleted once no other guild links it.
+    pub deleted: Vec<Membership>,
+    /// Members whose role couldn't be removed, with the error. Their memberships are kept so the
+    /// next prune tries again.
+    pub failed: Vec<(String, Error)>,
+}
+
+impl PruneOutcome {
+    /// A line per failed removal, or nothing if there were none.
+    pub fn failure_report(&self) -> String {
+        self.failed
+            .iter()
+            .map(|(tag, e)| format!("\nFailed to prune {}: {}", tag, e))
+            .collect()
+    }
+}
+
+impl PrunePlan {
+    /// Plans a prune of the holders of `member_role` among `members` against the stored
+    /// `memberships`. Bots and members without the role are never touched.
+    ///
+    /// Without a cutoff every holder lacking a current linked membership is pruned, which is what
+    /// `/prune` does. With a cutoff only memberships flagged at or before it are pruned, leaving
+    /// holders who were never linked alone.
+    pub fn new(
+        members: Vec<Member>,
+        memberships: Vec<Membership>,
+        member_role: RoleId,
+        type_roles: Vec<RoleId>,
+        cutoff: Option<i64>,
+    ) -> Self {
+        let expired = |m: &Membership| {
+            m.should_drop
+                && match cutoff {
+                    Some(cutoff) => m.dropped_at.is_none_or(|at| at <= cutoff),
+                    None => true,
+                }
+        };
+        let linked: HashMap<u64, &Membership> = memberships
+            .iter()
+            .filter_map(|m| Some((m.discord_id?, m)))
+            .collect();
+        let targets = members
+            .into_iter()
+            .filter(|member| !member.user.bot && member.roles.contains(&member_role))
+            .filter_map(|member| {
+                let membership = linked.get(member.user.id.as_u64()).copied();
+                let prune = match membership {
+                    Some(membership) => expired(membership),
+                    None => cutoff.is_none(),
+                };
+                prune.then(|| PruneTarget {
+                    member,
+                    membership: membership.cloned(),
+                })
+            })
+            .collect();
+        let memberships = memberships
+            .into_iter()
+            .filter(|m| m.discord_id.is_some() && expired(m))
+            .collect();
+        Self {
+            member_role,
+            type_roles,
+            targets,
+            memberships,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.targets.is_empty() && self.memberships.is_empty()
+    }
+
+    /// One line per member explaining why they will be pruned.
+    pub fn report(&self) -> String {
+        self.targets
+            .iter()
+            .map(|t| {
+                format!(
+                    "{} ({}): {}",
+                    t.member.display_name(),
+                    t.member.user.tag(),
+                    t.reason()
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Removes the member role and any type roles, and unlinks the expired memberships.
+    ///
+    /// Role removals are sent in small batches with a pause in between, so a large prune doesn't
+    /// spend its time hitting Discord's rate limits, and a failed removal doesn't stop the rest.
+    pub async fn execute(
+        self,
+        http: &Http,
+        config: &Config,
+        actor_id: Option<u64>,
+    ) -> Result<PruneOutcome> {
+        let mut outcome = PruneOutcome {
+            pruned: 0,
+            deleted: vec![],
+            failed: vec![],
+        };
+        let mut kept = HashSet::new();
+        for (i, batch) in self.targets.chunks(PRUNE_BATCH_SIZE).enumerate() {
+            if i > 0 {
+                tokio::time::sleep(PRUNE_BATCH_PAUSE).await;
+            }
+            for target in batch {
+                let mut member = target.member.clone();
+                let roles: Vec<RoleId> = std::iter::once(self.member_role)
+                    .chain(
+                        self.type_roles
+                            .iter()
+                            .filter(|r| member.roles.contains(r))
+                            .copied(),
+                    )
+                    .collect();
+                match member.remove_roles(http, &roles).await {
+                    Ok(_) => {
+                        outcome.pruned += 1;
+                        log::info!("Removing roles from {}", member.user.name);
+                    }
+                    Err(e) => {
+                        log::error!("Failed to remove roles from {}: {}", member.user.name, e);
+                        kept.insert(*member.user.id.as_u64());
+                        outcome.failed.push((member.user.tag(), e.into()));
+                    }
+                }
+            }
+        }
+
+        let conn = config.get_sqlite_conn()?;
+        for membership in self.memberships {
+            if membership.discord_id.is_some_and(|id| kept.contains(&id)) {
+                continue;
+            }
+            let mut unlinked = membership.clone();
+            unlinked.unlink(&conn, config.guild_id)?;
+            // Guilds sharing the memberships may still have the student registered
+            if !unlinked.is_linked(&conn)? {
+                membership.delete(&conn)?;
+            }
+            MembershipEvent::record(
+                &conn,
+                membership.guild_id,
+                membership.student_id,
+                membership.discord_id,
+                EventKind::Pruned,
+                actor_id,
+            )?;
+            outcome.deleted.push(membership);
+        }
+        Ok(outcome)
+    }
+}
+
+/// Prunes memberships that have been flagged for longer than the grace period in every server with
+/// a committee channel, and posts a summary there.
+pub async fn run_scheduled(config: Config) {
+    let guilds = match guild_members::fetch_guilds(&config.get_http()).await {
+        Ok(guilds) => guilds,
+        Err(e) => {
+            log::error!("Automatic prune failed to list servers: {}", e);
+            return;
+        }
+    };
+    for guild in guilds {
+        let result = match config.for_guild(*guild.id.as_u64()) {
+            Ok(config) => prune_scheduled(&config, guild.id).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            log::error!("Automatic prune of {} failed: {}", guild.name, e);
+        }
+    }
+}
+
+async fn prune_scheduled(config: &Config, guild_id: GuildId) -> Result<()> {
+    let http = config.get_http();
+    let channel_id = match config.committee_channel_id {
+        Some(channel_id) => ChannelId(channel_id),
+        None => return Ok(()),
+    };
+    if !config.channel_in_guild(channel_id, guild_id).await? {
+        return Ok(());
+    }
+    let roles = guild_id.roles(&http).await?;
+    let member_role = config.member_role(&roles)?;
+    let type_roles = config.type_role_map(&roles).all();
+
+    let memberships =
+        Membership::get_all(&config.get_sqlite_conn()?, config.scope, config.guild_id)?;
+    let members = guild_members::fetch_all(&http, guild_id).await?;
+    let cutoff = unix_now() - i64::from(config.auto_prune_grace_days) * 24 * 60 * 60;
+    let plan = PrunePlan::new(members, memberships, member_role, type_roles, Some(cutoff));
+    if plan.is_empty() {
+        log::info!("Automatic prune found nothing to do");
+        return Ok(());
+    }
+
+    let names: Vec<String> = plan
+        .targets
+        .iter()
+        .map(|t| t.member.display_name().to_string())
+        .collect();
+    let outcome = match plan.execute(&http, config, None).await {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            audit_log::log_action(config, guild_id, AuditEntry::failure("Prune", None, &e)).await;
+            return Err(e);
+        }
+    };
+    let mut summary = format!(
+        "Automatic prune: removed the member role from {} users and deleted {} memberships that lapsed over {} days ago",
+        outcome.pruned,
+        outcome.deleted.len(),
+        config.auto_prune_grace_days
+    );
+    if !names.is_empty() {
+        summary.push_str(&format!("\n{}", names.join(", ")));
+    }
+    summary.push_str(&outcome.failure_report());
+    audit_log::log_action(config, guild_id, AuditEntry::prune(None, &outcome)).await;
+    channel_id.say(&http, summary).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::membership::Membership;
+    use crate::prune::{PrunePlan, PruneSchedule};
+    use chrono::Weekday;
+    use poise::serenity_prelude::{Member, RoleId};
+    use serde_json::json;
+
+    const MEMBER_ROLE: RoleId = RoleId(100);
+
+    fn member(id: u64, roles: &[u64], bot: bool) -> Member {
+        serde_json::from_value(json!({
+            "guild_id": "1",
+            "deaf": false,
+            "mute": false,
+            "joined_at": null,
+            "roles": roles.iter().map(|r| r.to_string()).collect::<Vec<_>>(),
+            "user": {
+                "id": id.to_string(),
+                "username": format!("user{}", id),
+                "discriminator": "0001",
+                "avatar": null,
+                "bot": bot,
+            },
+        }))
+        .unwrap()
+    }
+
+    fn membership(student_id: u32, discord_id: u64, dropped_at: Option<i64>) -> Membership {
+        Membership {
+            student_id,
+            name: format!("Student {}", student_id),
+            discord_id: Some(discord_id),
+            should_drop: dropped_at.is_some(),
+            dropped_at,
+            ..Default::default()
+        }
+    }
+
+    fn target_ids(plan: &PrunePlan) -> Vec<u64> {
+        plan.targets
+            .iter()
+            .map(|t| *t.member.user.id.as_u64())
+            .collect()
+    }
+
+    #[test]
+    fn plan_only_role_holders() {
+        let members = vec![
+            member(1, &[100], false),
+            member(2, &[100], false),
+            member(3, &[100], false),
+            member(4, &[], false),
+            member(5, &[100], true),
+        ];
+        let memberships = vec![
+            membership(11, 1, None),
+            membership(12, 2, Some(1000)),
+            membership(14, 4, Some(1000)),
+            membership(16, 6, Some(1000)),
+        ];
+        let plan = PrunePlan::new(members, memberships, MEMBER_ROLE, vec![], None);
+        assert_eq!(target_ids(&plan), vec![2, 3]);
+        assert_eq!(
+            plan.memberships
+                .iter()
+                .map(|m| m.student_id)
+                .collect::<Vec<_>>(),
+            vec![12, 14, 16]
+        );
+        assert!(plan.report().contains("membership 12 has lapsed"));
+        assert!(plan.report().contains("not registered to a membership"));
+    }
+
+    #[test]
+    fn plan_with_grace_period() {
+        let members = vec![
+            member(1, &[100], false),
+            member(2, &[100], false),
+            member(3, &[100], false),
+        ];
+        let memberships = vec![membership(11, 1, Some(1000)), membership(12, 2, Some(5000))];
+        let plan = PrunePlan::new(members, memberships, MEMBER_ROLE, vec![], Some(2000));
+        assert_eq!(target_ids(&plan), vec![1]);
+        assert_eq!(plan.memberships.len(), 1);
+    }
+
+    #[test]
+    fn parse_schedule() {
+        assert_eq!(
+            "every 6 hours".parse::<PruneSchedule>().unwrap(),
+            PruneSchedule::Hours(6)
+        );
+        assert_eq!(
+            "daily 03:30".parse::<PruneSchedule>().unwrap(),
+            PruneSchedule::Daily(3, 30)
+        );
+        assert_eq!(
+            "weekly monday 18:00".parse::<PruneSchedule>().unwrap(),
+            PruneSchedule::Weekly(Weekday::Mon, 18, 0)
+        );
+        assert!("every 0 hours".parse::<PruneSchedule>().is_err());
+        assert!("daily 25:00".parse::<PruneSchedule>().is_err());
+        assert!("0 3 * * *".parse::<PruneSchedule>().is_err());
+    }
+}
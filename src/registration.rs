@@ -0,0 +1,104 @@
+use anyhow::Result;
+use rusqlite::{params, Connection};
+
+use crate::config::Config;
+use crate::guild_members;
+
+/// The guild run by whoever runs Bruce: `HOME_GUILD_ID`, or the only guild Bruce is in.
+pub async fn home_guild(config: &Config) -> Result<Option<u64>> {
+    if config.home_guild_id.is_some() {
+        return Ok(config.home_guild_id);
+    }
+    let guilds = guild_members::fetch_guilds(&config.get_http()).await?;
+    Ok(match guilds.as_slice() {
+        [guild] => Some(*guild.id.as_u64()),
+        _ => None,
+    })
+}
+
+/// Moves links to the shared memberships made before they were kept per guild into the home
+/// guild, which is the only one that could have made them unless others were added since.
+pub fn adopt_legacy(config: &Config) -> Result<()> {
+    let conn = config.get_sqlite_conn()?;
+    let count = |conn: &Connection| -> Result<usize> {
+        Ok(conn.query_row(
+            "SELECT COUNT(*) FROM registrations WHERE guild_id = 0",
+            params![],
+            |r| r.get(0),
+        )?)
+    };
+    if count(&conn)? == 0 {
+        return Ok(());
+    }
+    let home_guild_id = match config.home_guild_id {
+        Some(guild_id) => guild_id,
+        None => {
+            log::error!(
+                "{} registrations from before servers kept their own aren't in any server yet, set HOME_GUILD_ID to the server they were made in",
+                count(&conn)?
+            );
+            return Ok(());
+        }
+    };
+    // Links whose account is already registered in the home guild again are left where they are
+    let adopted = conn.execute(
+        "UPDATE OR IGNORE registrations SET guild_id = ?1 WHERE guild_id = 0",
+        params![home_guild_id],
+    )?;
+    log::info!("Moved {} old registrations to {}", adopted, home_guild_id);
+    let left = count(&conn)?;
+    if left > 0 {
+        log::warn!(
+            "{} old registrations clash with ones made in {} since, so they were left out",
+            left,
+            home_guild_id
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::membership::Membership;
+    use crate::migrations::migrate;
+    use crate::registration::adopt_legacy;
+
+    #[test]
+    fn adopt_legacy_links() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::test(dir.path());
+        let mut conn = config.get_sqlite_conn().unwrap();
+        migrate(&mut conn).unwrap();
+        for student_id in [1, 2] {
+            let mut membership = Membership {
+                student_id,
+                name: "Ada Lovelace".to_string(),
+                ..Default::default()
+            };
+            membership.insert(&conn).unwrap();
+            membership.link(&conn, 0, 40 + student_id as u64).unwrap();
+        }
+        Membership::get_by_student_id(&conn, 0, 10, 2)
+            .unwrap()
+            .link(&conn, 10, 42)
+            .unwrap();
+        let linked = |guild_id: u64, student_id: u32| {
+            Membership::get_by_student_id(&conn, 0, guild_id, student_id)
+                .unwrap()
+                .discord_id
+        };
+
+        adopt_legacy(&config).unwrap();
+        assert_eq!(linked(10, 1), None);
+
+        adopt_legacy(&Config {
+            home_guild_id: Some(10),
+            ..config.clone()
+        })
+        .unwrap();
+        assert_eq!(linked(10, 1), Some(41));
+        assert_eq!(linked(10, 2), Some(42));
+        assert_eq!(linked(0, 2), Some(42));
+    }
+}
@@ -0,0 +1,554 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error, Result};
+use chrono::{NaiveDate, NaiveDateTime};
+use reqwest::Url;
+use scraper::{ElementRef, Html, Selector};
+
+use crate::membership::Membership;
+
+/// A membership detail read from a column of the members table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    StudentId,
+    Name,
+    MembershipType,
+    PurchasedAt,
+    ExpiresAt,
+    Email,
+}
+
+const FIELDS: [Field; 6] = [
+    Field::StudentId,
+    Field::Name,
+    Field::MembershipType,
+    Field::PurchasedAt,
+    Field::ExpiresAt,
+    Field::Email,
+];
+
+impl Field {
+    /// The name used for this field in `SCRAPE_COLUMNS`.
+    pub fn key(self) -> &'static str {
+        match self {
+            Field::StudentId => "student_id",
+            Field::Name => "name",
+            Field::MembershipType => "membership_type",
+            Field::PurchasedAt => "purchased_at",
+            Field::ExpiresAt => "expires_at",
+            Field::Email => "email",
+        }
+    }
+
+    /// Header names recognised for this field, lowercase.
+    fn headers(self) -> &'static [&'static str] {
+        match self {
+            Field::StudentId => &["student id", "student number", "id"],
+            Field::Name => &["name", "full name", "member name"],
+            Field::MembershipType => &["type", "membership type", "membership", "product"],
+            Field::PurchasedAt => &["purchased", "purchase date", "date purchased", "bought"],
+            Field::ExpiresAt => &["expires", "expiry", "expiry date", "end date"],
+            Field::Email => &["email", "email address"],
+        }
+    }
+
+    fn required(self) -> bool {
+        matches!(self, Field::StudentId | Field::Name)
+    }
+
+    /// Where the field is when the table has no headers, matching the SUMS layout.
+    fn default_index(self) -> usize {
+        FIELDS.iter().position(|f| *f == self).unwrap()
+    }
+}
+
+impl FromStr for Field {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        FIELDS
+            .into_iter()
+            .find(|f| f.key() == s)
+            .ok_or_else(|| anyhow!("Unknown column {}", s))
+    }
+}
+
+/// Where a field is in the table: a 1-based column number or a header name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Column {
+    Index(usize),
+    Header(String),
+}
+
+/// How to find memberships on the members page, configured with the `SCRAPE_*` variables.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScrapeLayout {
+    pub table_selector: String,
+    /// Selects the rows within the table.
+    pub row_selector: String,
+    /// Selects the cells within a row.
+    pub cell_selector: String,
+    /// Selects the header cells within the table.
+    pub header_selector: String,
+    /// Columns pinned by `SCRAPE_COLUMNS`, the rest are found by header name.
+    pub columns: Vec<(Field, Column)>,
+    /// Selects the link to the next page of members, if the list is paginated.
+    pub next_selector: String,
+    /// Selects the element saying how many members there are, e.g. "Showing 1 to 50 of 120".
+    pub count_selector: String,
+    /// DataTables endpoint serving the rows, relative to the members page. Found from the table's
+    /// `data-ajax-url`, `data-source` or `data-url` attribute if not set.
+    pub datatable_url: Option<String>,
+}
+
+impl Default for ScrapeLayout {
+    fn default() -> Self {
+        Self {
+            table_selector: "#group-member-list-datatable".to_string(),
+            row_selector: "tbody > tr".to_string(),
+            cell_selector: "td".to_string(),
+            header_selector: "thead th".to_string(),
+            columns: vec![],
+            next_selector: "a[rel=next]".to_string(),
+            count_selector: ".dataTables_info, [data-member-count]".to_string(),
+            datatable_url: None,
+        }
+    }
+}
+
+/// Returned when the members page doesn't have the structure the layout expects.
+#[derive(Debug)]
+pub struct LayoutMismatch {
+    pub problem: String,
+    /// What was found on the page, one line per finding.
+    pub findings: Vec<String>,
+}
+
+impl Display for LayoutMismatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Members page doesn't match the scrape layout, {}",
+            self.problem
+        )?;
+        for finding in &self.findings {
+            write!(f, "\n- {}", finding)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for LayoutMismatch {}
+
+impl ScrapeLayout {
+    /// Checks the selectors parse.
+    pub fn validate(self) -> Result<Self> {
+        for selector in [
+            &self.table_selector,
+            &self.row_selector,
+            &self.cell_selector,
+            &self.header_selector,
+            &self.next_selector,
+            &self.count_selector,
+        ] {
+            selector_for(selector)?;
+        }
+        Ok(self)
+    }
+
+    /// Finds the members table on the page, if there is one.
+    pub fn table<'a>(&self, html: &'a Html) -> Option<ElementRef<'a>> {
+        html.select(&selector_for(&self.table_selector).ok()?)
+            .next()
+    }
+
+    /// The header texts of `table`.
+    pub fn headers(&self, table: ElementRef) -> Result<Vec<String>> {
+        let sel_header = selector_for(&self.header_selector)?;
+        Ok(table.select(&sel_header).map(cell_text).collect())
+    }
+
+    /// Reads the memberships out of `table`, explaining what was found if it doesn't fit.
+    pub fn extract(
+        &self,
+        table: ElementRef,
+        dates: &mut UnrecognisedDates,
+    ) -> Result<Vec<Membership>> {
+        let sel_row = selector_for(&self.row_selector)?;
+        let sel_cell = selector_for(&self.cell_selector)?;
+        let rows: Vec<Vec<String>> = table
+            .select(&sel_row)
+            .map(|row| row.select(&sel_cell).map(cell_text).collect())
+            .collect();
+        self.extract_rows(&self.headers(table)?, &rows, dates)
+    }
+
+    /// The link to the next page of members on `html`, fetched from `page_url`.
+    pub fn next_page(&self, html: &Html, page_url: &Url) -> Option<Url> {
+        let href = html
+            .select(&selector_for(&self.next_selector).ok()?)
+            .next()?
+            .value()
+            .attr("href")?;
+        page_url.join(href).ok()
+    }
+
+    /// How many members `html` says there are, taken as the last number in the count element.
+    pub fn advertised_count(&self, html: &Html) -> Option<usize> {
+        let element = html
+            .select(&selector_for(&self.count_selector).ok()?)
+            .next()?;
+        let count = element
+            .value()
+            .attr("data-member-count")
+            .map(str::to_string)
+            .unwrap_or_else(|| cell_text(element));
+        count
+            .replace(',', "")
+            .split(|c: char| !c.is_ascii_digit())
+            .rfind(|n| !n.is_empty())?
+            .parse()
+            .ok()
+    }
+
+    /// The DataTables endpoint serving `table`'s rows, if it's loaded that way.
+    pub fn datatable_source(&self, table: ElementRef, page_url: &Url) -> Option<Url> {
+        let url = match &self.datatable_url {
+            Some(url) => url.as_str(),
+            None => ["data-ajax-url", "data-source", "data-url"]
+                .iter()
+                .find_map(|attr| table.value().attr(attr))?,
+        };
+        page_url.join(url).ok()
+    }
+
+    /// Reads memberships from rows of cell texts under `headers`, explaining what was found if
+    /// they don't fit.
+    pub fn extract_rows(
+        &self,
+        headers: &[String],
+        rows: &[Vec<String>],
+        dates: &mut UnrecognisedDates,
+    ) -> Result<Vec<Membership>> {
+        let columns = self.resolve(headers);
+        let mismatch = |problem: String| -> Error {
+            let mut findings = vec![
+                format!("table `{}` found", self.table_selector),
+                format!(
+                    "headers `{}`: {}",
+                    self.header_selector,
+                    if headers.is_empty() {
+                        "none".to_string()
+                    } else {
+                        headers.join(", ")
+                    }
+                ),
+                format!(
+                    "rows `{}` with cells `{}`: {} rows, first has {} cells",
+                    self.row_selector,
+                    self.cell_selector,
+                    rows.len(),
+                    rows.first().map_or(0, Vec::len)
+                ),
+            ];
+            findings.extend(
+                FIELDS
+                    .iter()
+                    .zip(&columns)
+                    .map(|(field, index)| match index {
+                        Some(i) => format!("{} read from column {}", field.key(), i + 1),
+                        None => format!("{} not found", field.key()),
+                    }),
+            );
+            LayoutMismatch { problem, findings }.into()
+        };
+
+        for field in FIELDS.iter().filter(|f| f.required()) {
+            if columns[field.default_index()].is_none() {
+                return Err(mismatch(format!("no {} column", field.key())));
+            }
+        }
+        let mut memberships = vec![];
+        for (i, row) in rows.iter().enumerate() {
+            let cell = |field: Field| {
+                columns[field.default_index()]
+                    .and_then(|c| row.get(c))
+                    .filter(|text| !text.is_empty())
+                    .cloned()
+            };
+            let required = |field: Field| {
+                cell(field).ok_or_else(|| {
+                    mismatch(format!(
+                        "row {} has no {} in column {}",
+                        i + 1,
+                        field.key(),
+                        columns[field.default_index()].unwrap() + 1
+                    ))
+                })
+            };
+            let student_id = required(Field::StudentId)?;
+            memberships.push(Membership {
+                student_id: student_id.parse().map_err(|_| {
+                    mismatch(format!(
+                        "row {} has student_id {:?}, which isn't a number",
+                        i + 1,
+                        student_id
+                    ))
+                })?,
+                name: required(Field::Name)?,
+                membership_type: cell(Field::MembershipType),
+                purchased_at: cell(Field::PurchasedAt).and_then(|date| dates.parse(&date)),
+                expires_at: cell(Field::ExpiresAt).and_then(|date| dates.parse(&date)),
+                email: cell(Field::Email).filter(|email| email.contains('@')),
+                ..Default::default()
+            });
+        }
+        Ok(memberships)
+    }
+
+    /// The 0-based column of each field in `FIELDS` order, given the table's `headers`.
+    fn resolve(&self, headers: &[String]) -> Vec<Option<usize>> {
+        let lower: Vec<String> = headers.iter().map(|h| h.to_lowercase()).collect();
+        let mut columns: Vec<Option<usize>> = FIELDS
+            .iter()
+            .map(|field| {
+                match self.columns.iter().find(|(f, _)| f == field) {
+                    Some((_, Column::Index(i))) => return Some(i - 1),
+                    Some((_, Column::Header(header))) => {
+                        return lower.iter().position(|h| *h == header.to_lowercase())
+                    }
+                    None => {}
+                }
+                if headers.is_empty() {
+                    return Some(field.default_index());
+                }
+                field
+                    .headers()
+                    .iter()
+                    .find_map(|alias| lower.iter().position(|h| h == alias))
+            })
+            .collect();
+        // SUMS may word its headers differently to the aliases, so assume the required columns are
+        // where they've always been rather than stop syncing
+        for field in FIELDS.iter().filter(|f| f.required()) {
+            let index = field.default_index();
+            let pinned = self.columns.iter().any(|(f, _)| f == field);
+            if columns[index].is_none() && !pinned && !columns.contains(&Some(index)) {
+                log::warn!(
+                    "No header matches {}, assuming it's column {}. Set SCRAPE_COLUMNS if that's wrong",
+                    field.key(),
+                    index + 1
+                );
+                columns[index] = Some(index);
+            }
+        }
+        columns
+    }
+}
+
+/// Parses `SCRAPE_COLUMNS`, which looks like `student_id=Student ID; name=2`, with numbers being
+/// 1-based column positions.
+pub fn parse_columns(columns: &str) -> Result<Vec<(Field, Column)>> {
+    columns
+        .split(';')
+        .map(str::trim)
+        .filter(|c| !c.is_empty())
+        .map(|entry| {
+            let (field, column) = entry
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Expected `<column>=<header or number>`, got {}", entry))?;
+            let column = match column.trim().parse::<usize>() {
+                Ok(0) => return Err(anyhow!("Column numbers start at 1, got {}", entry)),
+                Ok(i) => Column::Index(i),
+                Err(_) => Column::Header(column.trim().to_string()),
+            };
+            Ok((field.trim().parse()?, column))
+        })
+        .collect()
+}
+
+fn selector_for(selector: &str) -> Result<Selector> {
+    Selector::parse(selector).map_err(|e| anyhow!("Invalid selector `{}`: {:?}", selector, e))
+}
+
+fn cell_text(element: ElementRef) -> String {
+    element.text().collect::<String>().trim().to_string()
+}
+
+/// The text of a cell that may contain markup, as DataTables endpoints often send.
+pub fn html_text(html: &str) -> String {
+    cell_text(Html::parse_fragment(html).root_element())
+}
+
+/// Parses a date as SUMS shows it into a unix timestamp, treating it as UTC.
+fn parse_date(date: &str) -> Option<i64> {
+    const DATE_TIMES: &[&str] = &["%d/%m/%Y %H:%M", "%d/%m/%Y %H:%M:%S", "%Y-%m-%d %H:%M:%S"];
+    const DATES: &[&str] = &["%d/%m/%Y", "%Y-%m-%d", "%d %B %Y", "%d %b %Y"];
+    let parsed = DATE_TIMES
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(date, format).ok())
+        .or_else(|| {
+            DATES.iter().find_map(|format| {
+                NaiveDate::parse_from_str(date, format)
+                    .ok()
+                    .and_then(|date| date.and_hms_opt(0, 0, 0))
+            })
+        });
+    parsed.map(|date| date.and_utc().timestamp())
+}
+
+/// Counts the dates no format matched during a scrape, so they're reported once rather than for
+/// every row.
+#[derive(Debug, Default)]
+pub struct UnrecognisedDates {
+    count: usize,
+    example: Option<String>,
+}
+
+impl UnrecognisedDates {
+    fn parse(&mut self, date: &str) -> Option<i64> {
+        let parsed = parse_date(date);
+        if parsed.is_none() {
+            self.count += 1;
+            self.example.get_or_insert_with(|| date.to_string());
+        }
+        parsed
+    }
+
+    /// Logs how many dates were ignored, if any were.
+    pub fn warn(&self) {
+        if let Some(example) = &self.example {
+            log::warn!(
+                "Ignored {} unrecognised dates from SUMS, such as {:?}",
+                self.count,
+                example
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::scrape_layout::{
+        parse_columns, parse_date, Column, Field, LayoutMismatch, ScrapeLayout, UnrecognisedDates,
+    };
+    use scraper::Html;
+
+    fn extract(
+        layout: &ScrapeLayout,
+        html: &str,
+    ) -> anyhow::Result<Vec<(u32, String, Option<String>)>> {
+        let html = Html::parse_document(html);
+        let table = layout.table(&html).expect("table");
+        Ok(layout
+            .extract(table, &mut Default::default())?
+            .into_iter()
+            .map(|m| (m.student_id, m.name, m.email))
+            .collect())
+    }
+
+    #[test]
+    fn columns_by_header() {
+        let html = "<table id=\"group-member-list-datatable\"><thead><tr><th>Name</th><th>Email Address</th><th>Student Number</th></tr></thead>\
+            <tbody><tr><td>Alice Smith</td><td>alice@example.com</td><td>12345678</td></tr></tbody></table>";
+        assert_eq!(
+            extract(&ScrapeLayout::default(), html).unwrap(),
+            vec![(
+                12345678,
+                "Alice Smith".to_string(),
+                Some("alice@example.com".to_string())
+            )]
+        );
+
+        let html =
+            "<div class=\"members\"><p><span>Alice Smith</span><span>12345678</span></p></div>";
+        let layout = ScrapeLayout {
+            table_selector: "div.members".to_string(),
+            row_selector: "p".to_string(),
+            cell_selector: "span".to_string(),
+            columns: parse_columns("student_id=2; name = 1").unwrap(),
+            ..Default::default()
+        }
+        .validate()
+        .unwrap();
+        assert_eq!(layout.columns[0], (Field::StudentId, Column::Index(2)));
+        assert_eq!(
+            extract(&layout, html).unwrap(),
+            vec![(12345678, "Alice Smith".to_string(), None)]
+        );
+
+        assert!(ScrapeLayout {
+            table_selector: "[".to_string(),
+            ..Default::default()
+        }
+        .validate()
+        .is_err());
+        assert!(parse_columns("student=1").is_err());
+        assert!(parse_columns("name=0").is_err());
+    }
+
+    #[test]
+    fn mismatch_report() {
+        let html = "<table id=\"group-member-list-datatable\"><thead><tr><th>Member</th><th>Name</th></tr></thead>\
+            <tbody><tr><td>12345678</td><td>Alice Smith</td></tr></tbody></table>";
+        // Unrecognised headers fall back to the usual SUMS columns
+        assert_eq!(
+            extract(&ScrapeLayout::default(), html).unwrap(),
+            vec![(12345678, "Alice Smith".to_string(), None)]
+        );
+        let unknown = html.replace("<th>Name</th>", "<th>Who</th>");
+        assert_eq!(
+            extract(&ScrapeLayout::default(), &unknown).unwrap(),
+            vec![(12345678, "Alice Smith".to_string(), None)]
+        );
+
+        // but not onto a column another field was found in
+        let taken = html.replace("<th>Member</th>", "<th>Name</th>");
+        let err = extract(&ScrapeLayout::default(), &taken).unwrap_err();
+        let mismatch = err.downcast_ref::<LayoutMismatch>().unwrap();
+        assert_eq!(mismatch.problem, "no student_id column");
+        assert!(mismatch
+            .findings
+            .contains(&"headers `thead th`: Name, Name".to_string()));
+        assert!(mismatch
+            .findings
+            .contains(&"name read from column 1".to_string()));
+
+        // nor in place of a column pinned by header
+        let layout = ScrapeLayout {
+            columns: parse_columns("student_id=Student Number").unwrap(),
+            ..Default::default()
+        };
+        let err = extract(&layout, html).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<LayoutMismatch>().unwrap().problem,
+            "no student_id column"
+        );
+
+        let layout = ScrapeLayout {
+            columns: parse_columns("student_id=Member").unwrap(),
+            ..Default::default()
+        };
+        assert!(extract(&layout, html).is_ok());
+        let html = html.replace("12345678", "Bob");
+        let err = extract(&layout, &html).unwrap_err();
+        assert!(err.to_string().contains("row 1 has student_id \"Bob\""));
+    }
+
+    #[test]
+    fn parse_dates() {
+        assert_eq!(parse_date("31/08/2023"), Some(1693440000));
+        assert_eq!(parse_date("2023-08-31"), Some(1693440000));
+        assert_eq!(parse_date("31 August 2023"), Some(1693440000));
+        assert_eq!(parse_date("01/09/2022 10:30"), Some(1662028200));
+        assert_eq!(parse_date("soon"), None);
+
+        let mut dates = UnrecognisedDates::default();
+        assert_eq!(dates.parse("31/08/2023"), Some(1693440000));
+        assert_eq!(dates.parse("soon"), None);
+        assert_eq!(dates.parse("later"), None);
+        assert_eq!(dates.count, 2);
+        assert_eq!(dates.example.as_deref(), Some("soon"));
+    }
+}
@@ -1,151 +1,1486 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
 use std::sync::Arc;
 
-use crate::config::Config;
+use crate::audit_log;
+use crate::committee;
+use crate::config::{Config, MembersPage, SumsLogin};
 use crate::cookie_database::CookieDatabase;
-use anyhow::{anyhow, Error, Result};
-use reqwest::{Client, StatusCode};
+use crate::guild_config::GuildConfig;
+use crate::membership_source::{self, MembershipSource, SourceKind};
+use anyhow::{anyhow, Context, Error, Result};
+use async_trait::async_trait;
+use reqwest::cookie::{CookieStore, Jar};
+use reqwest::{Client, StatusCode, Url};
+use rusqlite::{Connection, Transaction, TransactionBehavior};
 use scraper::Selector;
 
-use crate::membership::Membership;
+use crate::membership::{Membership, ANY_GUILD};
+use crate::membership_event::{EventKind, MembershipEvent};
+use crate::scrape_layout::{html_text, LayoutMismatch, ScrapeLayout, UnrecognisedDates};
+use crate::source_health::SourceHealth;
+use crate::type_roles;
+
+/// Scrapes the members page of a SUMS student dashboard group, authenticating with the session
+/// cookie stored in the `cookies` table.
+pub struct SumsSource {
+    page: MembersPage,
+    login: Option<SumsLogin>,
+    layout: ScrapeLayout,
+    cookie_db: Arc<CookieDatabase>,
+    client: Client,
+}
+
+impl SumsSource {
+    /// A source for each of the configured members pages, all sharing the stored session.
+    pub fn all(config: &Config) -> Result<Vec<Self>> {
+        let cookie_db = Arc::new(CookieDatabase::new(
+            config.get_sqlite_conn()?,
+            config.cookie_cipher.clone(),
+        )?);
+        let client = Client::builder()
+            .cookie_provider(cookie_db.clone())
+            .build()?;
+        Ok(config
+            .members_pages
+            .iter()
+            .map(|page| Self {
+                page: page.clone(),
+                login: config.sums_login.clone(),
+                layout: config.scrape_layout.clone(),
+                cookie_db: cookie_db.clone(),
+                client: client.clone(),
+            })
+            .collect())
+    }
+
+    /// Tags memberships found on this source's page with its tag.
+    fn tag(&self, memberships: &mut [Membership]) {
+        for membership in memberships {
+            membership.tags = self.page.tag.iter().cloned().collect();
+        }
+    }
+
+    async fn scrape(&self) -> Result<Vec<Membership>> {
+        let mut memberships =
+            scrape_memberships(&self.page.url, &self.client, &self.layout).await?;
+        self.tag(&mut memberships);
+        Ok(memberships)
+    }
+}
+
+#[async_trait]
+impl MembershipSource for SumsSource {
+    fn name(&self) -> String {
+        self.page.url.to_string()
+    }
+
+    async fn fetch(&self) -> Result<Vec<Membership>> {
+        let err = match self.scrape().await {
+            Err(err) if err.downcast_ref::<NotAuthenticated>().is_some() => err,
+            result => return result,
+        };
+        let login = match &self.login {
+            Some(login) => login,
+            None => return Err(err),
+        };
+        log::info!("SUMS session expired, logging in as {}", login.username);
+        if let Err(e) = sums_login(&self.client, login).await {
+            log::error!("Failed to log in to SUMS: {:#}", e);
+            return Err(err);
+        }
+        self.scrape().await
+    }
+}
+
+/// Returned when SUMS doesn't accept our session, meaning committee needs to supply a new cookie.
+#[derive(Debug)]
+pub struct NotAuthenticated(pub &'static str);
+
+impl Display for NotAuthenticated {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Failed to scrape members, {}", self.0)
+    }
+}
+
+impl std::error::Error for NotAuthenticated {}
 
 pub async fn init(config: Config) -> Result<()> {
-    let cookie_db = Arc::new(CookieDatabase::new(config.get_sqlite_conn()?));
-    let client = Client::builder()
-        .cookie_provider(cookie_db.clone())
-        .build()?;
+    match config.membership_source {
+        SourceKind::Sums => init_session(&config, &SumsSource::all(&config)?).await?,
+        _ => sync(&config, &membership_source::sources(&config)?).await?,
+    }
+    for config in guild_scopes(&config) {
+        if let Err(e) = sync(&config, &membership_source::sources(&config)?).await {
+            log::error!(
+                "Failed to sync memberships of guild {}: {:#}",
+                config.scope,
+                e
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The configs of the guilds that have their own members pages, which are synced separately from
+/// `MEMBERS_URL`.
+fn guild_scopes(config: &Config) -> Vec<Config> {
+    let guilds = match config
+        .get_sqlite_conn()
+        .and_then(|conn| GuildConfig::get_all(&conn))
+    {
+        Ok(guilds) => guilds,
+        Err(e) => {
+            log::error!("Failed to load guild configs: {}", e);
+            return vec![];
+        }
+    };
+    guilds
+        .into_iter()
+        .filter(|guild| guild.members_url.is_some())
+        .filter_map(|guild| {
+            match guild.apply(config).and_then(|config| {
+                // Pages set before they had to be allowed mustn't get the shared session either
+                config.check_members_pages(&config.members_pages)?;
+                Ok(config)
+            }) {
+                Ok(config) => Some(config),
+                Err(e) => {
+                    log::error!("Invalid config for guild {}: {}", guild.guild_id, e);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+async fn init_session(config: &Config, sources: &[SumsSource]) -> Result<()> {
+    // Every page shares the session, so find one that works on the first page before the rest
+    let primary = &sources[0];
     let mut memberships = Err(Error::msg("No memberships"));
-    if let Ok(cookie) = cookie_db.get_cookie_value(&config.members_url) {
-        log::info!("Trying saved cookie: {}", cookie);
-        memberships = scrape_memberships(&config, &client).await;
-    }
-    if memberships.is_err() || memberships.as_ref().unwrap().is_empty() {
-        log::info!("Trying initial cookie: {}", &config.initial_cookie_value);
-        cookie_db.add_cookie(
-            &config.members_url,
+    if primary
+        .cookie_db
+        .get_cookie_value(config.session_url(), "su_session")
+        .is_ok()
+        || primary.login.is_some()
+    {
+        log::info!("Trying saved cookie");
+        memberships = primary.fetch().await;
+    }
+    if memberships.is_err() && !config.initial_cookie_value.is_empty() {
+        log::info!("Trying initial cookie");
+        primary.cookie_db.add_cookie(
+            config.session_url(),
             "su_session",
             &config.initial_cookie_value,
         )?;
-        memberships = scrape_memberships(&config, &client).await;
+        memberships = primary.fetch().await;
+    }
+    let failed = memberships.is_err();
+    let result = sync_with(config, sources, memberships).await;
+    if failed {
+        return result
+            .context("Failed to scrape members with known cookies, try obtaining another one");
     }
-    if let Err(err) = memberships {
-        log::error!("{}", err);
+    result
+}
+
+/// Replaces the stored SUMS session with `cookie`, but only if SUMS accepts it. Returns how many
+/// memberships the test scrape found, along with how the sync that follows went, which can still
+/// fail on the other members pages once the cookie is saved.
+pub async fn set_session(config: &Config, cookie: &str) -> Result<(usize, Result<()>)> {
+    if config.membership_source != SourceKind::Sums {
         return Err(anyhow!(
-            "Failed to scrape members with known cookies, try obtaining another one",
+            "Memberships aren't scraped from SUMS, so there's no session to set"
         ));
     }
-    run().await;
-    Ok(())
+    // Test the cookie in a throwaway jar so a bad value never replaces a working one
+    let jar = Arc::new(Jar::default());
+    jar.add_cookie_str(
+        &format!("su_session={}; Path=/", cookie),
+        config.session_url(),
+    );
+    let client = Client::builder().cookie_provider(jar.clone()).build()?;
+    let sources = SumsSource::all(config)?;
+    let primary = &sources[0];
+    // A bad cookie says nothing about the stored session, so leave the source health alone
+    let mut memberships =
+        scrape_memberships(&primary.page.url, &client, &config.scrape_layout).await?;
+    let count = memberships.len();
+    primary.tag(&mut memberships);
+    // SUMS may have rotated the session while we were testing it
+    let session = jar
+        .cookies(config.session_url())
+        .and_then(|header| {
+            header
+                .to_str()
+                .ok()?
+                .split("; ")
+                .find_map(|c| c.strip_prefix("su_session=").map(|value| value.to_string()))
+        })
+        .unwrap_or_else(|| cookie.to_string());
+    primary
+        .cookie_db
+        .add_cookie(config.session_url(), "su_session", &session)?;
+    Ok((count, sync_with(config, &sources, Ok(memberships)).await))
 }
 
-pub async fn run() {
-    let config = Config::generate();
-    let cookie_jar = Arc::new(CookieDatabase::new(match config.get_sqlite_conn() {
-        Ok(conn) => conn,
-        Err(e) => {
-            log::error!("{}", e);
-            return;
-        }
-    }));
-    let client = match Client::builder()
-        .cookie_provider(cookie_jar.clone())
-        .build()
-    {
-        Ok(client) => client,
+pub async fn run(config: Config) {
+    let guilds = guild_scopes(&config);
+    run_scope(config).await;
+    for config in guilds {
+        run_scope(config).await;
+    }
+}
+
+async fn run_scope(config: Config) {
+    let sources = match membership_source::sources(&config) {
+        Ok(sources) => sources,
         Err(e) => {
             log::error!("{}", e);
             return;
         }
     };
-    let memberships = match scrape_memberships(&config, &client).await {
-        Ok(memberships) => memberships,
-        Err(e) => {
-            log::error!("{}", e);
-            return;
+    let result = sync(&config, &sources).await;
+    if let Err(e) = &result {
+        log::error!("{:#}", e);
+    }
+    if result.is_ok() && !config.membership_type_roles.is_empty() {
+        if let Err(e) = type_roles::sync(&config).await {
+            log::error!("Failed to sync type roles: {}", e);
+        }
+    }
+}
+
+/// Records the outcome of a fetch from `source`, alerting committee when the SUMS session has
+/// expired or the page layout has changed (repeating every `SESSION_ALERT_INTERVAL_HOURS`) and
+/// again once it recovers.
+async fn update_health(
+    config: &Config,
+    source: &dyn MembershipSource,
+    result: &Result<()>,
+) -> Result<()> {
+    let (alert, failing) = {
+        let conn = config.get_sqlite_conn()?;
+        let mut health = SourceHealth::get(&conn, &source.name())?;
+        match result {
+            Ok(()) => {
+                let recovered = !health.healthy && health.last_alerted.is_some();
+                health.record_success(&conn)?;
+                let alert = recovered.then(|| {
+                    format!(
+                        "Membership source {} is working again :tada:",
+                        source.name()
+                    )
+                });
+                (alert, false)
+            }
+            Err(e) => {
+                health.record_failure(&conn, &e.to_string())?;
+                let interval = i64::from(config.session_alert_interval_hours) * 60 * 60;
+                let alert = if !health.alert_due(interval) {
+                    None
+                } else if e.downcast_ref::<NotAuthenticated>().is_some() {
+                    Some(session_expired_alert(config, &health, e))
+                } else {
+                    e.downcast_ref::<LayoutMismatch>()
+                        .map(|mismatch| layout_mismatch_alert(config, &health, mismatch))
+                };
+                let alerted = alert.is_some();
+                (alert, alerted)
+            }
         }
     };
-    let conn = match config.get_sqlite_conn() {
-        Ok(client) => client,
-        Err(e) => {
-            log::error!("{}", e);
-            return;
+    let alert = match alert {
+        Some(alert) => alert,
+        None => return Ok(()),
+    };
+    if config.committee_channel_id.is_none() {
+        log::warn!("{}", alert);
+        return Ok(());
+    }
+    committee::notify(config, &alert).await?;
+    if failing {
+        SourceHealth::get(&config.get_sqlite_conn()?, &source.name())?
+            .record_alert(&config.get_sqlite_conn()?)?;
+    }
+    Ok(())
+}
+
+fn layout_mismatch_alert(
+    config: &Config,
+    health: &SourceHealth,
+    mismatch: &LayoutMismatch,
+) -> String {
+    let alert = format!(
+        ":warning: {} doesn't look like it used to, so the membership list isn't updating.",
+        health.source
+    );
+    // What was found on a guild's own page stays in the logs, as the `SCRAPE_*` settings are only
+    // for whoever runs Bruce to change
+    match config.scope {
+        0 => format!(
+            "{} Check the `SCRAPE_*` settings against the page.\n```\n{}\n```",
+            alert, mismatch
+        ),
+        _ => format!("{} Ask whoever runs Bruce to check it.", alert),
+    }
+}
+
+fn session_expired_alert(config: &Config, health: &SourceHealth, error: &Error) -> String {
+    let stale = match health.last_success {
+        Some(at) => format!("hasn't updated since <t:{}:R>", at),
+        None => "hasn't been scraped yet".to_string(),
+    };
+    // Only the environment's committee can replace the shared session with /set_cookie
+    let fix = match config.scope {
+        0 => {
+            "Log in to the student dashboard and give the new `su_session` cookie to `/set_cookie`."
         }
+        _ => "The session is shared with the rest of Bruce, so ask whoever runs it for a new one.",
     };
-    for mut membership in match Membership::get_all(&conn) {
-        Ok(client) => client,
-        Err(e) => {
-            log::error!("{}", e);
-            return;
+    format!(
+        ":warning: The SUMS session for {} has stopped working ({}), so the membership list {}.\n{}",
+        health.source,
+        error.root_cause(),
+        stale,
+        fix
+    )
+}
+
+/// Fetches the current memberships from every source and reconciles the `memberships` table with
+/// their union, recording each source's health.
+///
+/// Nothing changes unless every source worked, as the members of a failing source would otherwise
+/// look lapsed.
+pub async fn sync<S: MembershipSource>(config: &Config, sources: &[S]) -> Result<()> {
+    let first = match sources.first() {
+        Some(source) => source.fetch().await,
+        None => return Err(anyhow!("No membership sources configured")),
+    };
+    sync_with(config, sources, first).await
+}
+
+/// [`sync`] with the first source already fetched.
+async fn sync_with<S: MembershipSource>(
+    config: &Config,
+    sources: &[S],
+    first: Result<Vec<Membership>>,
+) -> Result<()> {
+    let mut fetched = vec![first];
+    for source in &sources[1..] {
+        fetched.push(source.fetch().await);
+    }
+    let mut lists = vec![];
+    let mut errors = vec![];
+    for (source, result) in sources.iter().zip(fetched) {
+        let result = result.map(|memberships| lists.push(memberships));
+        if let Err(e) = update_health(config, source, &result).await {
+            log::error!("Failed to update source health: {}", e);
         }
-    } {
-        let student_ids: HashSet<u32> = memberships.iter().map(|m| m.student_id).collect();
-        if !student_ids.contains(&membership.student_id) {
-            if membership.discord_id.is_none() {
-                match membership.delete(&conn) {
-                    Ok(client) => client,
-                    Err(e) => {
-                        log::error!("{}", e);
-                        continue;
-                    }
-                };
-            } else {
-                match membership.update_should_drop(&conn, true) {
-                    Ok(client) => client,
-                    Err(e) => {
-                        log::error!("{}", e);
-                        continue;
-                    }
-                };
-            }
+        if let Err(e) = result {
+            log::error!("Failed to fetch memberships from {}: {}", source.name(), e);
+            errors.push(e);
         }
     }
-    for membership in memberships {
-        match membership.insert(&conn) {
-            Ok(client) => client,
-            Err(e) => {
-                log::error!("{}", e);
+    let failed = errors.len();
+    if let Some(err) = errors.into_iter().next() {
+        return Err(err.context(format!(
+            "{} of {} membership sources failed, not updating memberships",
+            failed,
+            sources.len()
+        )));
+    }
+    let diff = apply_memberships(&mut config.get_sqlite_conn()?, config.scope, union(lists))?;
+    audit_log::log_sync(config, &diff).await;
+    Ok(())
+}
+
+/// Merges the memberships found by several sources. A membership on more than one page gets the
+/// tags of all of them, the first page's details and the latest expiry.
+pub fn union(lists: Vec<Vec<Membership>>) -> Vec<Membership> {
+    let mut merged: Vec<Membership> = vec![];
+    let mut positions = HashMap::new();
+    for membership in lists.into_iter().flatten() {
+        let existing = match positions.get(&membership.student_id) {
+            Some(&position) => &mut merged[position],
+            None => {
+                positions.insert(membership.student_id, merged.len());
+                merged.push(membership);
                 continue;
             }
         };
+        existing.membership_type = existing
+            .membership_type
+            .take()
+            .or(membership.membership_type);
+        existing.purchased_at = existing.purchased_at.or(membership.purchased_at);
+        existing.expires_at = existing.expires_at.max(membership.expires_at);
+        existing.email = existing.email.take().or(membership.email);
+        for tag in membership.tags {
+            if !existing.tags.contains(&tag) {
+                existing.tags.push(tag);
+            }
+        }
     }
+    merged
 }
 
-async fn scrape_memberships(config: &Config, client: &Client) -> Result<Vec<Membership>, Error> {
-    let request = client.get(config.members_url.clone()).build()?;
-    let response = client.execute(request).await?;
+/// The changes needed to bring the `memberships` table in line with a freshly fetched list.
+#[derive(Debug, Default, PartialEq)]
+pub struct MembershipDiff {
+    /// Memberships that aren't in the table yet.
+    pub joined: Vec<Membership>,
+    /// Linked memberships that have lapsed and should be flagged for `/prune`.
+    pub dropped: Vec<Membership>,
+    /// Unlinked memberships that have lapsed and can simply be deleted.
+    pub removed: Vec<Membership>,
+    /// Memberships flagged for dropping that are back on the list, as they should be stored.
+    pub renewed: Vec<Membership>,
+    /// Current memberships whose name or details changed on SUMS, as they should be stored.
+    pub updated: Vec<Membership>,
+}
 
-    if response.status() != StatusCode::OK {
+impl MembershipDiff {
+    pub fn compute(existing: Vec<Membership>, fetched: Vec<Membership>) -> Self {
+        let existing_ids: HashSet<u32> = existing.iter().map(|m| m.student_id).collect();
+        let fetched_by_id: HashMap<u32, &Membership> =
+            fetched.iter().map(|m| (m.student_id, m)).collect();
+        let mut diff = Self::default();
+        for membership in existing {
+            if let Some(current) = fetched_by_id.get(&membership.student_id) {
+                let stored = Membership {
+                    student_id: membership.student_id,
+                    guild_id: membership.guild_id,
+                    discord_id: membership.discord_id,
+                    should_drop: false,
+                    dropped_at: None,
+                    ..(*current).clone()
+                };
+                if membership.should_drop {
+                    diff.renewed.push(stored);
+                } else if membership.details_differ(current) {
+                    diff.updated.push(stored);
+                }
+                continue;
+            }
+            if membership.discord_id.is_none() {
+                diff.removed.push(membership);
+            } else if !membership.should_drop {
+                diff.dropped.push(membership);
+            }
+        }
+        let mut joined_ids = HashSet::new();
+        for membership in fetched {
+            if !existing_ids.contains(&membership.student_id)
+                && joined_ids.insert(membership.student_id)
+            {
+                diff.joined.push(membership);
+            }
+        }
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.joined.is_empty()
+            && self.dropped.is_empty()
+            && self.removed.is_empty()
+            && self.renewed.is_empty()
+            && self.updated.is_empty()
+    }
+
+    /// Applies the diff in a single transaction, so either every change lands or none do.
+    /// Writes the changes in `tx`, which should be the transaction the memberships were read in.
+    pub fn apply(&self, tx: &Transaction) -> Result<()> {
+        for membership in &self.removed {
+            membership.delete(tx)?;
+            MembershipEvent::record(
+                tx,
+                membership.guild_id,
+                membership.student_id,
+                None,
+                EventKind::Lapsed,
+                None,
+            )?;
+        }
+        for membership in &self.dropped {
+            membership.clone().update_should_drop(tx, true)?;
+            MembershipEvent::record(
+                tx,
+                membership.guild_id,
+                membership.student_id,
+                membership.discord_id,
+                EventKind::Lapsed,
+                None,
+            )?;
+        }
+        for membership in &self.joined {
+            membership.insert(tx)?;
+            MembershipEvent::record(
+                tx,
+                membership.guild_id,
+                membership.student_id,
+                None,
+                EventKind::Joined,
+                None,
+            )?;
+        }
+        for membership in &self.renewed {
+            let mut stored = membership.clone();
+            stored.update_details(tx, membership)?;
+            stored.update_should_drop(tx, false)?;
+            MembershipEvent::record(
+                tx,
+                membership.guild_id,
+                membership.student_id,
+                membership.discord_id,
+                EventKind::Renewed,
+                None,
+            )?;
+        }
+        for membership in &self.updated {
+            membership.clone().update_details(tx, membership)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reconciles the memberships of `scope` with `memberships`, returning the changes made.
+fn apply_memberships(
+    conn: &mut Connection,
+    scope: u64,
+    mut memberships: Vec<Membership>,
+) -> Result<MembershipDiff> {
+    for membership in &mut memberships {
+        membership.guild_id = scope;
+    }
+    // Read in the same transaction as the writes, so a member linked by /register partway
+    // through can't be deleted as lapsed from a stale copy
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let diff = MembershipDiff::compute(Membership::get_all(&tx, scope, ANY_GUILD)?, memberships);
+    if diff.is_empty() {
+        return Ok(diff);
+    }
+    diff.apply(&tx)?;
+    tx.commit()?;
+    for membership in &diff.renewed {
+        log::info!(
+            "Membership {} ({}) renewed",
+            membership.student_id,
+            membership.name
+        );
+    }
+    log::info!(
+        "Synced memberships: {} joined, {} dropped, {} removed, {} renewed, {} updated",
+        diff.joined.len(),
+        diff.dropped.len(),
+        diff.removed.len(),
+        diff.renewed.len(),
+        diff.updated.len()
+    );
+    Ok(diff)
+}
+
+/// Logs in through the student dashboard login form, leaving the new session in `client`'s
+/// cookie store.
+pub async fn sums_login(client: &Client, login: &SumsLogin) -> Result<()> {
+    let response = client
+        .get(login.url.clone())
+        .send()
+        .await?
+        .error_for_status()?;
+    let page_url = response.url().clone();
+    let (action, fields) = login_form(&response.text().await?, &page_url, login)?;
+    let response = client
+        .post(action)
+        .form(&fields)
+        .send()
+        .await?
+        .error_for_status()?;
+    if has_login_form(&scraper::Html::parse_document(&response.text().await?)) {
         return Err(anyhow!(
-            "Failed to scrape members, status code: {}",
-            response.status()
+            "SUMS rejected the login, check SUMS_USERNAME and SUMS_PASSWORD"
         ));
     }
+    Ok(())
+}
+
+fn has_login_form(html: &scraper::Html) -> bool {
+    let sel_password =
+        Selector::parse("form input[type=password]").expect("Failed to create selector");
+    html.select(&sel_password).next().is_some()
+}
+
+/// Works out where the login form on `html` posts to and fills it in, keeping hidden fields such
+/// as the CSRF token.
+fn login_form(
+    html: &str,
+    page_url: &Url,
+    login: &SumsLogin,
+) -> Result<(Url, Vec<(String, String)>)> {
+    let html = scraper::Html::parse_document(html);
+    let sel_form = Selector::parse("form").expect("Failed to create selector");
+    let sel_password = Selector::parse("input[type=password]").expect("Failed to create selector");
+    let sel_input = Selector::parse("input[name]").expect("Failed to create selector");
+    let form = html
+        .select(&sel_form)
+        .find(|form| form.select(&sel_password).next().is_some())
+        .ok_or_else(|| anyhow!("No login form found at {}", page_url))?;
+    let action = match form.value().attr("action") {
+        Some(action) => page_url.join(action)?,
+        None => page_url.clone(),
+    };
 
-    let html = response.text().await?;
-    if html.contains("Sorry you're not authenticated") {
+    let mut fields = vec![];
+    let mut has_username = false;
+    for input in form.select(&sel_input).map(|e| e.value()) {
+        let name = input.attr("name").unwrap_or_default().to_string();
+        let value = input.attr("value").unwrap_or_default().to_string();
+        match input.attr("type").unwrap_or("text").to_lowercase().as_str() {
+            "password" => fields.push((name, login.password.clone())),
+            "text" | "email" if !has_username => {
+                has_username = true;
+                fields.push((name, login.username.clone()));
+            }
+            "submit" | "button" | "checkbox" | "radio" => {}
+            _ => fields.push((name, value)),
+        }
+    }
+    if !has_username {
         return Err(anyhow!(
-            "Failed to scrape members, cookie not providing authenticated access",
+            "No username field in the login form at {}",
+            page_url
         ));
     }
-    let html = scraper::Html::parse_document(&html);
-    let sel_tr = Selector::parse("#group-member-list-datatable > tbody > tr")
-        .expect("Failed to create selector");
-    let sel_td = Selector::parse("td").expect("Failed to create selector");
-    let mut memberships = vec![];
-    for tr in html.select(&sel_tr).map(|e| e.select(&sel_td)) {
-        let data: Vec<Option<&str>> = tr.take(2).map(|td| td.text().next()).collect();
-        memberships.push(Membership {
-            student_id: data[0]
-                .ok_or_else(|| anyhow!("Unexpected td value"))?
-                .parse()?,
-            name: data[1]
-                .ok_or_else(|| anyhow!("Unexpected td value"))?
-                .to_string(),
-            discord_id: None,
-            should_drop: false,
-        });
+    Ok((action, fields))
+}
+
+pub async fn scrape_memberships(
+    members_url: &Url,
+    client: &Client,
+    layout: &ScrapeLayout,
+) -> Result<Vec<Membership>> {
+    let mut dates = UnrecognisedDates::default();
+    let mut page = parse_page(&fetch_page(client, members_url).await?, layout, &mut dates)?;
+    let advertised = page.advertised;
+    let mut memberships = match page.datatable.take() {
+        Some(datatable) => {
+            let (memberships, total) =
+                scrape_datatable(client, &datatable, layout, &mut dates).await?;
+            if advertised.is_none() && total.is_some() {
+                check_count(memberships.len(), total)?;
+            }
+            memberships
+        }
+        None => {
+            let mut memberships = std::mem::take(&mut page.memberships);
+            let mut visited = HashSet::from([members_url.clone()]);
+            while let Some(next) = page.next.take() {
+                if !visited.insert(next.clone()) {
+                    break;
+                }
+                if visited.len() > PAGE_LIMIT {
+                    return Err(anyhow!(
+                        "Gave up after {} pages of {}, the member list would be incomplete",
+                        PAGE_LIMIT,
+                        members_url
+                    ));
+                }
+                page = parse_page(&fetch_page(client, &next).await?, layout, &mut dates)?;
+                memberships.append(&mut page.memberships);
+            }
+            memberships
+        }
+    };
+
+    let mut seen = HashSet::new();
+    memberships.retain(|m| seen.insert(m.student_id));
+    if memberships.is_empty() {
+        return Err(NotAuthenticated("members table is empty").into());
     }
+    check_count(memberships.len(), advertised)?;
 
+    dates.warn();
     log::info!("Scraped {} members", memberships.len());
     Ok(memberships)
 }
+
+/// Gives up following pagination links after this many pages, in case they never end.
+const PAGE_LIMIT: usize = 200;
+/// How many rows to ask a DataTables endpoint for at a time.
+const DATATABLE_PAGE_SIZE: usize = 500;
+
+/// One page of the members list.
+struct Page {
+    memberships: Vec<Membership>,
+    next: Option<Url>,
+    advertised: Option<usize>,
+    /// The DataTables endpoint to load the rows from, with the table's headers.
+    datatable: Option<(Url, Vec<String>)>,
+}
+
+/// Fetches `url`, returning the page's final URL and body once it's clear we're authenticated.
+async fn fetch_page(client: &Client, url: &Url) -> Result<(Url, String)> {
+    let response = client.get(url.clone()).send().await?;
+    if response.status() == StatusCode::UNAUTHORIZED || response.status() == StatusCode::FORBIDDEN {
+        return Err(NotAuthenticated("session rejected by the server").into());
+    }
+    if response.status() != StatusCode::OK {
+        return Err(anyhow!(
+            "Failed to scrape members, status code: {}",
+            response.status()
+        ));
+    }
+    if response.url().path() != url.path() && response.url().path().to_lowercase().contains("login")
+    {
+        return Err(NotAuthenticated("redirected to the login page").into());
+    }
+    let page_url = response.url().clone();
+    let body = response.text().await?;
+    if body.contains("Sorry you're not authenticated") {
+        return Err(NotAuthenticated("cookie not providing authenticated access").into());
+    }
+    Ok((page_url, body))
+}
+
+fn parse_page(
+    (page_url, body): &(Url, String),
+    layout: &ScrapeLayout,
+    dates: &mut UnrecognisedDates,
+) -> Result<Page> {
+    let html = scraper::Html::parse_document(body);
+    let table = match layout.table(&html) {
+        Some(table) => table,
+        None if has_login_form(&html) => {
+            return Err(NotAuthenticated("members table missing from the page").into())
+        }
+        None => {
+            return Err(LayoutMismatch {
+                problem: format!("no table matches `{}`", layout.table_selector),
+                findings: vec![format!(
+                    "page title: {}",
+                    html.select(&Selector::parse("title").expect("Failed to create selector"))
+                        .next()
+                        .map(|t| t.text().collect::<String>())
+                        .unwrap_or_default()
+                        .trim()
+                )],
+            }
+            .into())
+        }
+    };
+    let datatable = match layout.datatable_source(table, page_url) {
+        Some(url) => Some((url, layout.headers(table)?)),
+        None => None,
+    };
+    Ok(Page {
+        // Rows of a DataTables table are only placeholders until the endpoint fills them in
+        memberships: match datatable {
+            Some(_) => vec![],
+            None => layout.extract(table, dates)?,
+        },
+        next: layout.next_page(&html, page_url),
+        advertised: layout.advertised_count(&html),
+        datatable,
+    })
+}
+
+/// Loads every row from a DataTables server-side endpoint, returning them with the total the
+/// endpoint reported.
+async fn scrape_datatable(
+    client: &Client,
+    (url, headers): &(Url, Vec<String>),
+    layout: &ScrapeLayout,
+    dates: &mut UnrecognisedDates,
+) -> Result<(Vec<Membership>, Option<usize>)> {
+    let mut headers = headers.clone();
+    let mut rows = vec![];
+    // SUMS may return fewer rows than asked for, so a page is only short compared to the others
+    let mut page_size = 0;
+    for draw in 1..=PAGE_LIMIT {
+        let mut page_url = url.clone();
+        page_url
+            .query_pairs_mut()
+            .append_pair("draw", &draw.to_string())
+            .append_pair("start", &rows.len().to_string())
+            .append_pair("length", &DATATABLE_PAGE_SIZE.to_string());
+        let (_, body) = fetch_page(client, &page_url).await?;
+        let json: serde_json::Value = serde_json::from_str(&body)
+            .map_err(|e| anyhow!("Datatable endpoint {} didn't return JSON: {}", url, e))?;
+        let total = ["recordsFiltered", "recordsTotal", "iTotalRecords"]
+            .iter()
+            .find_map(|key| json[key].as_u64())
+            .map(|total| total as usize);
+        let page = json["data"]
+            .as_array()
+            .or_else(|| json["aaData"].as_array())
+            .ok_or_else(|| anyhow!("Datatable endpoint {} returned no data array", url))?;
+        for row in page {
+            rows.push(datatable_row(row, &mut headers)?);
+        }
+        page_size = page_size.max(page.len());
+        let done = match total {
+            Some(total) => rows.len() >= total,
+            None => page.len() < page_size,
+        };
+        if page.is_empty() || done {
+            return Ok((layout.extract_rows(&headers, &rows, dates)?, total));
+        }
+    }
+    Err(anyhow!(
+        "Gave up after {} requests to datatable endpoint {}, the member list would be incomplete",
+        PAGE_LIMIT,
+        url
+    ))
+}
+
+/// The cell texts of a DataTables row, which is either an array of cells or an object keyed by
+/// column. Object keys become the headers.
+fn datatable_row(row: &serde_json::Value, headers: &mut Vec<String>) -> Result<Vec<String>> {
+    let text = |value: &serde_json::Value| match value {
+        serde_json::Value::String(s) => html_text(s),
+        serde_json::Value::Null => String::new(),
+        value => value.to_string(),
+    };
+    match row {
+        serde_json::Value::Array(cells) => Ok(cells.iter().map(text).collect()),
+        serde_json::Value::Object(fields) => {
+            if headers.is_empty() || !headers.iter().all(|h| fields.contains_key(h)) {
+                *headers = fields.keys().cloned().collect();
+            }
+            Ok(headers.iter().map(|h| text(&fields[h])).collect())
+        }
+        _ => Err(anyhow!("Unexpected datatable row {}", row)),
+    }
+}
+
+/// Makes sure we got every member the page said there were, so a partial scrape isn't taken as
+/// everyone else lapsing.
+fn check_count(scraped: usize, advertised: Option<usize>) -> Result<()> {
+    match advertised {
+        Some(advertised) if advertised != scraped => Err(anyhow!(
+            "Scraped {} members but SUMS says there are {}, not updating",
+            scraped,
+            advertised
+        )),
+        _ => Ok(()),
+    }
+}
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::cookie_database::CookieDatabase;
+    use crate::fake_sums::FakeSums;
+    use crate::guild_config::GuildConfig;
+    use crate::membership::{Membership, ANY_GUILD};
+    use crate::membership_event::{EventKind, MembershipEvent};
+    use crate::membership_source::MembershipSource;
+    use crate::migrations::migrate;
+    use crate::scrape_layout::ScrapeLayout;
+    use crate::scraper::{
+        apply_memberships, init, run, scrape_memberships, session_expired_alert, set_session, sync,
+        MembershipDiff, NotAuthenticated, SumsSource, PAGE_LIMIT,
+    };
+    use crate::source_health::SourceHealth;
+    use reqwest::Client;
+    use rusqlite::{params, Connection};
+    use tempfile::TempDir;
+
+    fn setup(server: &FakeSums, initial_cookie_value: &str) -> (TempDir, Config) {
+        let dir = tempfile::tempdir().unwrap();
+        let config = server.config(dir.path(), initial_cookie_value);
+        migrate(&mut config.get_sqlite_conn().unwrap()).unwrap();
+        (dir, config)
+    }
+
+    fn membership(student_id: u32, discord_id: Option<u64>, should_drop: bool) -> Membership {
+        Membership {
+            student_id,
+            name: format!("Student {}", student_id),
+            discord_id,
+            should_drop,
+            ..Default::default()
+        }
+    }
+
+    fn memberships(config: &Config) -> Vec<(u32, String, Option<u64>, bool)> {
+        let mut memberships: Vec<_> =
+            Membership::get_all(&config.get_sqlite_conn().unwrap(), config.scope, ANY_GUILD)
+                .unwrap()
+                .into_iter()
+                .map(|m| (m.student_id, m.name, m.discord_id, m.should_drop))
+                .collect();
+        memberships.sort();
+        memberships
+    }
+
+    #[tokio::test]
+    async fn init_with_initial_cookie() {
+        let server = FakeSums::start("session").await;
+        server.set_members(&[(12345678, "Alice Smith"), (87654321, "Bob Jones")]);
+        let (_dir, config) = setup(&server, "session");
+        init(config.clone()).await.unwrap();
+        assert_eq!(
+            memberships(&config),
+            vec![
+                (12345678, "Alice Smith".to_string(), None, false),
+                (87654321, "Bob Jones".to_string(), None, false),
+            ]
+        );
+        let alice = Membership::get_by_student_id(
+            &config.get_sqlite_conn().unwrap(),
+            0,
+            ANY_GUILD,
+            12345678,
+        )
+        .unwrap();
+        assert_eq!(alice.membership_type.as_deref(), Some("Full Membership"));
+        assert_eq!(alice.purchased_at, Some(1662028200));
+        assert_eq!(alice.expires_at, Some(1693440000));
+        assert_eq!(alice.email.as_deref(), Some("12345678@nottingham.ac.uk"));
+    }
+
+    #[test]
+    fn session_alert_by_scope() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::test(dir.path());
+        let mut conn = config.get_sqlite_conn().unwrap();
+        migrate(&mut conn).unwrap();
+        let health = SourceHealth::get(&conn, "page").unwrap();
+        let error = anyhow::Error::new(NotAuthenticated("redirected to login"));
+        let alert = |config: &Config| session_expired_alert(config, &health, &error);
+        assert!(alert(&config).contains("`/set_cookie`"));
+        let guild = alert(&Config {
+            scope: 42,
+            ..config.clone()
+        });
+        assert!(!guild.contains("`/set_cookie`"));
+        assert!(guild.contains("ask whoever runs it"));
+    }
+
+    #[tokio::test]
+    async fn scrape_not_authenticated() {
+        let server = FakeSums::start("session").await;
+        server.set_members(&[(12345678, "Alice Smith")]);
+        let err = scrape_memberships(&server.members_url(), &Client::new(), &Default::default())
+            .await
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("not providing authenticated access"));
+    }
+
+    #[tokio::test]
+    async fn scrape_login_redirect() {
+        let server = FakeSums::start("session").await;
+        server.set_members(&[(12345678, "Alice Smith")]);
+        server.set_login_redirect(true);
+        let err = scrape_memberships(&server.members_url(), &Client::new(), &Default::default())
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<NotAuthenticated>().is_some());
+        assert!(err.to_string().contains("login page"));
+    }
+
+    #[tokio::test]
+    async fn scrape_empty_table() {
+        let server = FakeSums::start("session").await;
+        let client = Client::builder().cookie_store(true).build().unwrap();
+        let url = server.members_url();
+        let err = scrape_memberships(&url, &client, &Default::default())
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<NotAuthenticated>().is_some());
+
+        let (_dir, config) = setup(&server, "session");
+        let err = init(config.clone()).await.unwrap_err();
+        assert!(err.downcast_ref::<NotAuthenticated>().is_some());
+        assert!(err
+            .root_cause()
+            .to_string()
+            .contains("members table is empty"));
+    }
+
+    #[tokio::test]
+    async fn run_tracks_source_health() {
+        let server = FakeSums::start("session").await;
+        server.set_members(&[(12345678, "Alice Smith")]);
+        let (_dir, config) = setup(&server, "session");
+        init(config.clone()).await.unwrap();
+        let source = config.session_url().to_string();
+        let health = SourceHealth::get(&config.get_sqlite_conn().unwrap(), &source).unwrap();
+        assert!(health.healthy);
+        assert!(health.last_success.is_some());
+
+        server.set_session("expired");
+        run(config.clone()).await;
+        let health = SourceHealth::get(&config.get_sqlite_conn().unwrap(), &source).unwrap();
+        assert!(!health.healthy);
+        assert!(health.unhealthy_since.is_some());
+        assert!(health
+            .last_error
+            .unwrap()
+            .contains("not providing authenticated access"));
+        assert_eq!(memberships(&config).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn scrape_layout_mismatch() {
+        let server = FakeSums::start("session").await;
+        server.set_members(&[(12345678, "Alice Smith")]);
+        let (_dir, config) = setup(&server, "session");
+        init(config.clone()).await.unwrap();
+        let layout = ScrapeLayout {
+            table_selector: "#members".to_string(),
+            ..Default::default()
+        };
+        let sources = SumsSource::all(&Config {
+            scrape_layout: layout,
+            ..config.clone()
+        })
+        .unwrap();
+        let err = sources[0].fetch().await.unwrap_err();
+        assert!(err.downcast_ref::<NotAuthenticated>().is_none());
+        assert!(err.to_string().contains("no table matches `#members`"));
+    }
+
+    async fn scrape_all(server: &FakeSums) -> anyhow::Result<Vec<u32>> {
+        let cookie = reqwest::header::HeaderValue::from_static("su_session=session");
+        let client = Client::builder()
+            .default_headers([(reqwest::header::COOKIE, cookie)].into_iter().collect())
+            .build()
+            .unwrap();
+        let memberships =
+            scrape_memberships(&server.members_url(), &client, &Default::default()).await?;
+        Ok(memberships.iter().map(|m| m.student_id).collect())
+    }
+
+    #[tokio::test]
+    async fn scrape_follows_pages() {
+        let server = FakeSums::start("session").await;
+        let members: Vec<(u32, &str)> = (0..7).map(|i| (12345670 + i, "Member")).collect();
+        server.set_members(&members);
+        server.set_page_size(Some(3));
+        let ids = scrape_all(&server).await.unwrap();
+        assert_eq!(ids, members.iter().map(|(id, _)| *id).collect::<Vec<_>>());
+
+        server.set_advertised(Some(9));
+        let err = scrape_all(&server).await.unwrap_err();
+        assert!(err.to_string().contains("SUMS says there are 9"));
+
+        let members: Vec<(u32, &str)> = (0..=PAGE_LIMIT as u32)
+            .map(|i| (12345000 + i, "Member"))
+            .collect();
+        server.set_members(&members);
+        server.set_page_size(Some(1));
+        let err = scrape_all(&server).await.unwrap_err();
+        assert!(err.to_string().contains("Gave up after"));
+    }
+
+    #[tokio::test]
+    async fn scrape_datatable() {
+        let server = FakeSums::start("session").await;
+        let members: Vec<(u32, &str)> = (0..5).map(|i| (12345670 + i, "Member")).collect();
+        server.set_members(&members);
+        server.set_datatable(true);
+        server.set_page_size(Some(2));
+        let ids = scrape_all(&server).await.unwrap();
+        assert_eq!(ids, members.iter().map(|(id, _)| *id).collect::<Vec<_>>());
+
+        server.set_advertised(Some(6));
+        let err = scrape_all(&server).await.unwrap_err();
+        assert!(err.to_string().contains("SUMS says there are 6"));
+
+        server.set_advertised(None);
+        server.set_totals(false);
+        let ids = scrape_all(&server).await.unwrap();
+        assert_eq!(ids, members.iter().map(|(id, _)| *id).collect::<Vec<_>>());
+
+        server.set_session("expired");
+        let err = scrape_all(&server).await.unwrap_err();
+        assert!(err.downcast_ref::<NotAuthenticated>().is_some());
+    }
+
+    #[tokio::test]
+    async fn scrape_non_200() {
+        let server = FakeSums::start("session").await;
+        server.set_status(Some(503));
+        let err = scrape_memberships(&server.members_url(), &Client::new(), &Default::default())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("503"));
+    }
+
+    #[tokio::test]
+    async fn init_with_bad_cookie() {
+        let server = FakeSums::start("session").await;
+        server.set_members(&[(12345678, "Alice Smith")]);
+        let (_dir, config) = setup(&server, "expired");
+        assert!(init(config.clone()).await.is_err());
+        assert!(memberships(&config).is_empty());
+    }
+
+    #[tokio::test]
+    async fn init_prefers_saved_cookie() {
+        let server = FakeSums::start("saved").await;
+        server.set_members(&[(12345678, "Alice Smith")]);
+        let (_dir, config) = setup(&server, "expired");
+        CookieDatabase::new(
+            config.get_sqlite_conn().unwrap(),
+            config.cookie_cipher.clone(),
+        )
+        .unwrap()
+        .add_cookie(config.session_url(), "su_session", "saved")
+        .unwrap();
+        init(config.clone()).await.unwrap();
+        assert_eq!(server.received_cookies(), vec!["su_session=saved"]);
+        assert_eq!(memberships(&config).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn run_reconciles_memberships() {
+        let server = FakeSums::start("session").await;
+        server.set_members(&[
+            (12345678, "Alice Smith"),
+            (87654321, "Bob Jones"),
+            (11111111, "Carol White"),
+        ]);
+        let (_dir, config) = setup(&server, "session");
+        init(config.clone()).await.unwrap();
+        Membership::get_by_student_id(&config.get_sqlite_conn().unwrap(), 0, ANY_GUILD, 12345678)
+            .unwrap()
+            .link(&config.get_sqlite_conn().unwrap(), 10, 42)
+            .unwrap();
+
+        server.set_members(&[(11111111, "Carol White"), (22222222, "Dan Brown")]);
+        run(config.clone()).await;
+        assert_eq!(
+            memberships(&config),
+            vec![
+                (11111111, "Carol White".to_string(), None, false),
+                (12345678, "Alice Smith".to_string(), Some(42), true),
+                (22222222, "Dan Brown".to_string(), None, false),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn run_renews_returning_members() {
+        let server = FakeSums::start("session").await;
+        server.set_members(&[(12345678, "Alice Smith")]);
+        let (_dir, config) = setup(&server, "session");
+        init(config.clone()).await.unwrap();
+        let conn = config.get_sqlite_conn().unwrap();
+        Membership::get_by_student_id(&conn, 0, ANY_GUILD, 12345678)
+            .unwrap()
+            .link(&conn, 10, 42)
+            .unwrap();
+
+        server.set_members(&[(87654321, "Bob Jones")]);
+        run(config.clone()).await;
+        assert_eq!(
+            memberships(&config),
+            vec![
+                (12345678, "Alice Smith".to_string(), Some(42), true),
+                (87654321, "Bob Jones".to_string(), None, false),
+            ]
+        );
+
+        server.set_members(&[(12345678, "Alice Jones")]);
+        run(config.clone()).await;
+        assert_eq!(
+            memberships(&config),
+            vec![(12345678, "Alice Jones".to_string(), Some(42), false)]
+        );
+        assert_eq!(
+            MembershipEvent::get_by_student_id(&conn, 0, 12345678)
+                .unwrap()
+                .iter()
+                .map(|e| e.kind)
+                .collect::<Vec<_>>(),
+            vec![EventKind::Joined, EventKind::Lapsed, EventKind::Renewed]
+        );
+    }
+
+    #[tokio::test]
+    async fn run_unions_sources() {
+        let server = FakeSums::start("session").await;
+        server.set_members(&[(12345678, "Alice Smith"), (87654321, "Bob Jones")]);
+        let alumni = FakeSums::start("session").await;
+        alumni.set_members(&[(87654321, "Bob Jones"), (11111111, "Carol White")]);
+        let (_dir, config) = setup(&server, "session");
+        let config = Config {
+            members_pages: vec![
+                server.members_page(None),
+                alumni.members_page(Some("alumni")),
+            ],
+            ..config
+        };
+        init(config.clone()).await.unwrap();
+        let conn = config.get_sqlite_conn().unwrap();
+        let tags = |student_id| {
+            Membership::get_by_student_id(&conn, 0, ANY_GUILD, student_id)
+                .unwrap()
+                .tags
+        };
+        assert!(tags(12345678).is_empty());
+        assert_eq!(tags(87654321), vec!["alumni".to_string()]);
+        assert_eq!(tags(11111111), vec!["alumni".to_string()]);
+
+        // A failing source mustn't make its members look lapsed
+        alumni.set_status(Some(500));
+        server.set_members(&[(12345678, "Alice Smith")]);
+        run(config.clone()).await;
+        assert_eq!(memberships(&config).len(), 3);
+        let health = |source: &FakeSums| {
+            SourceHealth::get(&conn, source.members_url().as_str())
+                .unwrap()
+                .healthy
+        };
+        assert!(health(&server));
+        assert!(!health(&alumni));
+
+        alumni.set_status(None);
+        run(config.clone()).await;
+        assert!(health(&alumni));
+        assert_eq!(
+            memberships(&config)
+                .into_iter()
+                .map(|m| m.0)
+                .collect::<Vec<_>>(),
+            vec![11111111, 12345678, 87654321]
+        );
+        assert_eq!(tags(12345678), Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn run_syncs_guild_scopes() {
+        let server = FakeSums::start("session").await;
+        server.set_members(&[(12345678, "Alice Smith")]);
+        let other = FakeSums::start("session").await;
+        other.set_members(&[(87654321, "Bob Jones"), (11111111, "Carol White")]);
+        let (_dir, config) = setup(&server, "session");
+        let config = Config {
+            allowed_members_urls: vec![other.members_url().join("/").unwrap()],
+            ..config
+        };
+        let guild = GuildConfig {
+            guild_id: 42,
+            members_url: Some(other.members_url().to_string()),
+            ..Default::default()
+        };
+        guild.save(&config.get_sqlite_conn().unwrap()).unwrap();
+        // Set before pages had to be allowed, so it's never scraped
+        GuildConfig {
+            guild_id: 43,
+            members_url: Some(server.members_url().to_string()),
+            ..Default::default()
+        }
+        .save(&config.get_sqlite_conn().unwrap())
+        .unwrap();
+        init(config.clone()).await.unwrap();
+
+        let guild_config = config.for_guild(42).unwrap();
+        assert_eq!(guild_config.scope, 42);
+        let ids = |config: &Config| {
+            memberships(config)
+                .into_iter()
+                .map(|m| m.0)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids(&config), vec![12345678]);
+        assert_eq!(ids(&guild_config), vec![11111111, 87654321]);
+        assert_eq!(config.for_guild(7).unwrap().scope, 0);
+        assert_eq!(ids(&config.for_guild(43).unwrap()), Vec::<u32>::new());
+
+        other.set_members(&[(87654321, "Bob Jones")]);
+        run(config.clone()).await;
+        assert_eq!(ids(&config), vec![12345678]);
+        assert_eq!(ids(&guild_config), vec![87654321]);
+    }
+
+    #[tokio::test]
+    async fn run_keeps_table_on_failure() {
+        let server = FakeSums::start("session").await;
+        server.set_members(&[(12345678, "Alice Smith")]);
+        let (_dir, config) = setup(&server, "session");
+        init(config.clone()).await.unwrap();
+
+        server.set_status(Some(500));
+        let sources = SumsSource::all(&config).unwrap();
+        assert!(sync(&config, &sources).await.is_err());
+        assert_eq!(
+            memberships(&config),
+            vec![(12345678, "Alice Smith".to_string(), None, false)]
+        );
+    }
+
+    #[tokio::test]
+    async fn session_rotation() {
+        let server = FakeSums::start("session").await;
+        server.set_members(&[(12345678, "Alice Smith")]);
+        let (_dir, config) = setup(&server, "session");
+        init(config.clone()).await.unwrap();
+
+        server.rotate_session("rotated");
+        run(config.clone()).await;
+        run(config.clone()).await;
+        assert_eq!(
+            server.received_cookies(),
+            vec![
+                "su_session=session",
+                "su_session=session",
+                "su_session=rotated"
+            ]
+        );
+        assert_eq!(
+            CookieDatabase::new(
+                config.get_sqlite_conn().unwrap(),
+                config.cookie_cipher.clone()
+            )
+            .unwrap()
+            .get_cookie_value(config.session_url(), "su_session")
+            .unwrap(),
+            "rotated"
+        );
+        assert_eq!(memberships(&config).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn set_session_only_stores_working_cookies() {
+        let server = FakeSums::start("session").await;
+        server.set_members(&[(12345678, "Alice Smith")]);
+        let (_dir, config) = setup(&server, "session");
+        init(config.clone()).await.unwrap();
+        let stored = || {
+            CookieDatabase::new(
+                config.get_sqlite_conn().unwrap(),
+                config.cookie_cipher.clone(),
+            )
+            .unwrap()
+            .get_cookie_value(config.session_url(), "su_session")
+            .unwrap()
+        };
+
+        server.set_session("fresh");
+        server.set_members(&[(12345678, "Alice Smith"), (87654321, "Bob Jones")]);
+        assert!(set_session(&config, "wrong").await.is_err());
+        assert_eq!(stored(), "session");
+        assert_eq!(memberships(&config).len(), 1);
+        let source = config.session_url().to_string();
+        assert!(
+            SourceHealth::get(&config.get_sqlite_conn().unwrap(), &source)
+                .unwrap()
+                .healthy
+        );
+
+        server.rotate_session("rotated");
+        let (count, synced) = set_session(&config, "fresh").await.unwrap();
+        assert_eq!(count, 2);
+        assert!(synced.is_ok());
+        assert_eq!(stored(), "rotated");
+        assert_eq!(memberships(&config).len(), 2);
+        assert!(
+            SourceHealth::get(&config.get_sqlite_conn().unwrap(), &source)
+                .unwrap()
+                .healthy
+        );
+    }
+
+    #[tokio::test]
+    async fn login_when_session_expires() {
+        let server = FakeSums::start("session").await;
+        server.set_members(&[(12345678, "Alice Smith")]);
+        server.set_credentials("committee@example.com", "hunter2");
+        let (_dir, mut config) = setup(&server, "");
+        config.sums_login = Some(server.login("committee@example.com", "hunter2"));
+        init(config.clone()).await.unwrap();
+        assert_eq!(memberships(&config).len(), 1);
+
+        server.set_session("renewed");
+        server.set_members(&[(12345678, "Alice Smith"), (87654321, "Bob Jones")]);
+        run(config.clone()).await;
+        assert_eq!(memberships(&config).len(), 2);
+        assert_eq!(
+            CookieDatabase::new(
+                config.get_sqlite_conn().unwrap(),
+                config.cookie_cipher.clone()
+            )
+            .unwrap()
+            .get_cookie_value(config.session_url(), "su_session")
+            .unwrap(),
+            "renewed"
+        );
+    }
+
+    #[tokio::test]
+    async fn login_rejected() {
+        let server = FakeSums::start("session").await;
+        server.set_members(&[(12345678, "Alice Smith")]);
+        server.set_credentials("committee@example.com", "hunter2");
+        let (_dir, mut config) = setup(&server, "");
+        config.sums_login = Some(server.login("committee@example.com", "wrong"));
+        let err = init(config.clone()).await.unwrap_err();
+        assert!(err.downcast_ref::<NotAuthenticated>().is_some());
+        assert!(memberships(&config).is_empty());
+    }
+
+    #[test]
+    fn diff_compute() {
+        let existing = vec![
+            membership(1, None, false),
+            membership(2, Some(20), false),
+            membership(3, Some(30), true),
+            membership(4, Some(40), false),
+            membership(6, Some(60), true),
+            membership(7, Some(70), false),
+        ];
+        let renamed = Membership {
+            name: "Renamed".to_string(),
+            ..membership(6, None, false)
+        };
+        let associate = Membership {
+            membership_type: Some("Associate Membership".to_string()),
+            ..membership(7, None, false)
+        };
+        let fetched = vec![
+            membership(4, None, false),
+            membership(5, None, false),
+            renamed.clone(),
+            associate.clone(),
+        ];
+        assert_eq!(
+            MembershipDiff::compute(existing, fetched),
+            MembershipDiff {
+                joined: vec![membership(5, None, false)],
+                dropped: vec![membership(2, Some(20), false)],
+                removed: vec![membership(1, None, false)],
+                renewed: vec![Membership {
+                    discord_id: Some(60),
+                    ..renamed
+                }],
+                updated: vec![Membership {
+                    discord_id: Some(70),
+                    ..associate
+                }],
+            }
+        );
+    }
+
+    #[test]
+    fn diff_apply_is_atomic() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        membership(1, None, false).insert(&conn).unwrap();
+        membership(2, Some(20), false).insert(&conn).unwrap();
+        conn.execute(
+            "CREATE TRIGGER fail_insert BEFORE INSERT ON memberships WHEN NEW.student_id = 3 BEGIN SELECT RAISE(ABORT, 'boom'); END",
+            params![],
+        )
+        .unwrap();
+
+        let existing = Membership::get_all(&conn, 0, ANY_GUILD).unwrap();
+        assert!(apply_memberships(&mut conn, 0, vec![membership(3, None, false)]).is_err());
+        assert_eq!(Membership::get_all(&conn, 0, ANY_GUILD).unwrap(), existing);
+    }
+}
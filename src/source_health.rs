@@ -0,0 +1,133 @@
+use anyhow::Result;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+
+use crate::membership::unix_now;
+
+/// Whether a membership source's last fetch worked, kept in the `source_health` table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceHealth {
+    pub source: String,
+    pub healthy: bool,
+    pub last_error: Option<String>,
+    pub last_success: Option<i64>,
+    /// When the current run of failures started.
+    pub unhealthy_since: Option<i64>,
+    /// When committee was last told about the current run of failures.
+    pub last_alerted: Option<i64>,
+}
+
+const COLUMNS: &str = "source, healthy, last_error, last_success, unhealthy_since, last_alerted";
+
+impl SourceHealth {
+    /// The stored health of `source`, or a healthy record if it has never been fetched.
+    pub fn get(conn: &Connection, source: &str) -> Result<Self> {
+        let health = conn
+            .query_row(
+                &format!("SELECT {} FROM source_health WHERE source = ?1", COLUMNS),
+                params![source],
+                Self::from_row,
+            )
+            .optional()?;
+        Ok(health.unwrap_or_else(|| Self {
+            source: source.to_string(),
+            healthy: true,
+            last_error: None,
+            last_success: None,
+            unhealthy_since: None,
+            last_alerted: None,
+        }))
+    }
+
+    pub fn record_success(&mut self, conn: &Connection) -> Result<()> {
+        self.healthy = true;
+        self.last_error = None;
+        self.last_success = Some(unix_now());
+        self.unhealthy_since = None;
+        self.last_alerted = None;
+        self.save(conn)
+    }
+
+    pub fn record_failure(&mut self, conn: &Connection, error: &str) -> Result<()> {
+        if self.healthy {
+            self.unhealthy_since = Some(unix_now());
+        }
+        self.healthy = false;
+        self.last_error = Some(error.to_string());
+        self.save(conn)
+    }
+
+    pub fn record_alert(&mut self, conn: &Connection) -> Result<()> {
+        self.last_alerted = Some(unix_now());
+        self.save(conn)
+    }
+
+    /// Whether committee should be (re)alerted, at most once every `interval` seconds.
+    pub fn alert_due(&self, interval: i64) -> bool {
+        !self.healthy
+            && self
+                .last_alerted
+                .is_none_or(|alerted| unix_now() - alerted >= interval)
+    }
+
+    fn save(&self, conn: &Connection) -> Result<()> {
+        conn.execute(
+            &format!(
+                "INSERT OR REPLACE INTO source_health ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                COLUMNS
+            ),
+            params![
+                self.source,
+                self.healthy,
+                self.last_error,
+                self.last_success,
+                self.unhealthy_since,
+                self.last_alerted
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn from_row(r: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            source: r.get(0)?,
+            healthy: r.get(1)?,
+            last_error: r.get(2)?,
+            last_success: r.get(3)?,
+            unhealthy_since: r.get(4)?,
+            last_alerted: r.get(5)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::migrations::migrate;
+    use crate::source_health::SourceHealth;
+    use rusqlite::Connection;
+
+    #[test]
+    fn failure_and_recovery() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        let mut health = SourceHealth::get(&conn, "sums").unwrap();
+        assert!(health.healthy);
+        assert!(!health.alert_due(3600));
+
+        health.record_failure(&conn, "expired").unwrap();
+        let since = health.unhealthy_since;
+        assert!(since.is_some());
+        assert!(health.alert_due(3600));
+        health.record_alert(&conn).unwrap();
+        assert!(!health.alert_due(3600));
+        assert!(health.alert_due(0));
+
+        health.record_failure(&conn, "still expired").unwrap();
+        let stored = SourceHealth::get(&conn, "sums").unwrap();
+        assert_eq!(stored, health);
+        assert_eq!(stored.unhealthy_since, since);
+
+        health.record_success(&conn).unwrap();
+        assert_eq!(SourceHealth::get(&conn, "sums").unwrap(), health);
+        assert!(health.healthy);
+    }
+}
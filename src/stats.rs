@@ -0,0 +1,152 @@
+use anyhow::Result;
+
+use crate::config::Config;
+use crate::membership::{unix_now, Membership};
+use crate::membership_event::{EventKind, MembershipEvent};
+use crate::membership_source;
+use crate::source_health::SourceHealth;
+
+const WEEK: i64 = 7 * 24 * 60 * 60;
+const MONTH: i64 = 30 * 24 * 60 * 60;
+
+/// A summary of the memberships a guild uses, for `/stats`.
+#[derive(Debug, PartialEq)]
+pub struct MembershipStats {
+    /// Memberships found on SUMS, including lapsed ones still waiting to be pruned.
+    pub total: usize,
+    pub linked: usize,
+    pub should_drop: usize,
+    pub registered_last_week: usize,
+    pub registered_last_month: usize,
+    /// When every members page last scraped successfully, which is when the memberships were last
+    /// brought up to date.
+    pub last_scrape: Option<i64>,
+    /// The health of each members page.
+    pub sources: Vec<SourceHealth>,
+}
+
+impl MembershipStats {
+    pub fn get(config: &Config) -> Result<Self> {
+        let conn = config.get_sqlite_conn()?;
+        // Guilds sharing another's memberships only count the members registered with them
+        let linked_only = !config.sees_all_memberships();
+        let memberships: Vec<Membership> =
+            Membership::get_all(&conn, config.scope, config.guild_id)?
+                .into_iter()
+                .filter(|m| !linked_only || m.discord_id.is_some())
+                .collect();
+        let now = unix_now();
+        let registered_since = |since| {
+            if linked_only {
+                Membership::count_linked_since(&conn, config.scope, config.guild_id, since)
+            } else {
+                MembershipEvent::count_since(&conn, config.scope, EventKind::Registered, since)
+            }
+        };
+        let mut sources = membership_source::sources(config)?
+            .iter()
+            .map(|source| SourceHealth::get(&conn, &source.name()))
+            .collect::<Result<Vec<_>>>()?;
+        if linked_only {
+            // Errors can quote the members page, which is only for the guild that owns it
+            for health in &mut sources {
+                health.last_error = None;
+            }
+        }
+        Ok(Self {
+            total: memberships.len(),
+            linked: memberships
+                .iter()
+                .filter(|m| m.discord_id.is_some())
+                .count(),
+            should_drop: memberships.iter().filter(|m| m.should_drop).count(),
+            registered_last_week: registered_since(now - WEEK)?,
+            registered_last_month: registered_since(now - MONTH)?,
+            last_scrape: sources
+                .iter()
+                .map(|health| health.last_success)
+                .min()
+                .flatten(),
+            sources,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::membership::Membership;
+    use crate::membership_event::{EventKind, MembershipEvent};
+    use crate::migrations::migrate;
+    use crate::source_health::SourceHealth;
+    use crate::stats::MembershipStats;
+
+    #[test]
+    fn counts() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::test(dir.path());
+        let mut conn = config.get_sqlite_conn().unwrap();
+        migrate(&mut conn).unwrap();
+
+        let stats = MembershipStats::get(&config).unwrap();
+        assert_eq!(stats.total, 0);
+        assert_eq!(stats.last_scrape, None);
+
+        for (student_id, name) in [(11111111, "Ada"), (22222222, "Alan"), (33333333, "Grace")] {
+            Membership {
+                student_id,
+                name: name.to_string(),
+                ..Default::default()
+            }
+            .insert(&conn)
+            .unwrap();
+        }
+        Membership::get_by_student_id(&conn, 0, 0, 11111111)
+            .unwrap()
+            .link(&conn, 10, 42)
+            .unwrap();
+        MembershipEvent::record(
+            &conn,
+            0,
+            11111111,
+            Some(42),
+            EventKind::Registered,
+            Some(42),
+        )
+        .unwrap();
+        Membership::get_by_student_id(&conn, 0, 0, 22222222)
+            .unwrap()
+            .update_should_drop(&conn, true)
+            .unwrap();
+        SourceHealth::get(&conn, config.session_url().as_str())
+            .unwrap()
+            .record_success(&conn)
+            .unwrap();
+
+        let stats = MembershipStats::get(&config).unwrap();
+        assert_eq!(stats.total, 3);
+        assert_eq!(stats.linked, 1);
+        assert_eq!(stats.should_drop, 1);
+        assert_eq!(stats.registered_last_week, 1);
+        assert_eq!(stats.registered_last_month, 1);
+        assert!(stats.last_scrape.is_some());
+        assert_eq!(stats.sources.len(), 1);
+        assert!(stats.sources[0].healthy);
+
+        Membership::get_by_student_id(&conn, 0, 0, 22222222)
+            .unwrap()
+            .link(&conn, 20, 43)
+            .unwrap();
+        let sharing = |guild_id| {
+            MembershipStats::get(&Config {
+                guild_id,
+                ..config.clone()
+            })
+            .unwrap()
+        };
+        let stats = sharing(20);
+        assert_eq!((stats.total, stats.linked, stats.should_drop), (1, 1, 1));
+        assert_eq!(stats.registered_last_week, 1);
+        assert_eq!(sharing(30).total, 0);
+    }
+}
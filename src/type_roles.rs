@@ -0,0 +1,381 @@
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error, Result};
+use fallible_iterator::FallibleIterator;
+use poise::serenity_prelude::{GuildId, Http, Role, RoleId};
+use rusqlite::{params, Connection};
+
+use crate::config::Config;
+use crate::guild_members;
+use crate::membership::Membership;
+
+/// Extra roles given to registered members depending on their membership type, parsed from
+/// `MEMBERSHIP_TYPE_ROLES`.
+///
+/// The format is `<type>=<role>[,<role>...]` entries separated by `;`, e.g.
+/// `Full Membership=Full Member; Alumni=Alumni,Friends`. Types are matched case-insensitively,
+/// against both the membership type and the tags of the members pages the membership was on.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TypeRoles {
+    mapping: Vec<(String, Vec<String>)>,
+}
+
+impl FromStr for TypeRoles {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut mapping = vec![];
+        for entry in s.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+            let (membership_type, roles) = entry
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Expected `<type>=<roles>`, got {}", entry))?;
+            let roles: Vec<String> = roles
+                .split(',')
+                .map(str::trim)
+                .filter(|r| !r.is_empty())
+                .map(str::to_string)
+                .collect();
+            if membership_type.trim().is_empty() || roles.is_empty() {
+                return Err(anyhow!("Expected `<type>=<roles>`, got {}", entry));
+            }
+            mapping.push((membership_type.trim().to_lowercase(), roles));
+        }
+        Ok(Self { mapping })
+    }
+}
+
+impl TypeRoles {
+    pub fn is_empty(&self) -> bool {
+        self.mapping.is_empty()
+    }
+
+    /// Every role name the mapping hands out.
+    pub fn names(&self) -> Vec<&str> {
+        let mut seen = HashSet::new();
+        self.mapping
+            .iter()
+            .flat_map(|(_, names)| names)
+            .filter(|name| seen.insert(name.as_str()))
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// Looks up the mapped roles among a guild's `roles`, by the ID `pinned` for their name or by
+    /// the name itself until they're pinned. Roles that can't be found are left out with a
+    /// warning, so one missing role doesn't stop members getting the rest.
+    pub fn resolve(&self, roles: &HashMap<RoleId, Role>, pinned: &HashMap<String, u64>) -> RoleMap {
+        let mut mapping = vec![];
+        for (membership_type, names) in &self.mapping {
+            let ids = names
+                .iter()
+                .filter_map(|name| match find_type_role(roles, pinned, name) {
+                    Ok(id) => Some(id),
+                    Err(e) => {
+                        log::warn!("Skipping a role for {} memberships: {}", membership_type, e);
+                        None
+                    }
+                })
+                .collect();
+            mapping.push((membership_type.clone(), ids));
+        }
+        RoleMap { mapping }
+    }
+}
+
+/// Finds the type role called `name` by its pinned ID, falling back to the name if the pinned
+/// role has been deleted, in case it was recreated.
+fn find_type_role(
+    roles: &HashMap<RoleId, Role>,
+    pinned: &HashMap<String, u64>,
+    name: &str,
+) -> Result<RoleId> {
+    match pinned.get(name) {
+        Some(id) if roles.contains_key(&RoleId(*id)) => Ok(RoleId(*id)),
+        _ => role_by_name(roles, name),
+    }
+}
+
+/// The IDs of the type roles pinned in `guild_id`, by the name they're given in
+/// `MEMBERSHIP_TYPE_ROLES`.
+pub fn pinned(conn: &Connection, guild_id: u64) -> Result<HashMap<String, u64>> {
+    let mut stmt =
+        conn.prepare("SELECT role_name, role_id FROM type_role_ids WHERE guild_id = ?1")?;
+    let rows = stmt.query(params![guild_id])?;
+    Ok(rows.map(|r| Ok((r.get(0)?, r.get(1)?))).collect()?)
+}
+
+/// Pins the type roles of `guild_id` found among its `roles`, so renaming them later is safe.
+/// Returns the names that couldn't be found.
+pub fn pin(
+    conn: &Connection,
+    config: &Config,
+    guild_id: u64,
+    roles: &HashMap<RoleId, Role>,
+) -> Result<Vec<String>> {
+    let mut missing = vec![];
+    for name in config.membership_type_roles.names() {
+        match find_type_role(roles, &config.type_role_ids, name) {
+            Ok(id) if config.type_role_ids.get(name) != Some(id.as_u64()) => {
+                conn.execute(
+                    "INSERT OR REPLACE INTO type_role_ids (guild_id, role_name, role_id) VALUES (?1, ?2, ?3)",
+                    params![guild_id, name, id.as_u64()],
+                )?;
+            }
+            Ok(_) => {}
+            Err(e) => missing.push(format!("{}: {}", name, e)),
+        }
+    }
+    Ok(missing)
+}
+
+/// Finds the ID of the role called `name` among a guild's `roles`, refusing to guess between
+/// several with that name.
+pub fn role_by_name(roles: &HashMap<RoleId, Role>, name: &str) -> Result<RoleId> {
+    let mut matching = roles.values().filter(|r| r.name == name);
+    match (matching.next(), matching.next()) {
+        (Some(role), None) => Ok(role.id),
+        (Some(_), Some(_)) => Err(anyhow!(
+            "Several roles are called {}, rename all but one",
+            name
+        )),
+        (None, _) => Err(anyhow!("Role {} could not be found", name)),
+    }
+}
+
+/// [`TypeRoles`] resolved to the role IDs of one guild.
+#[derive(Debug, Clone, Default)]
+pub struct RoleMap {
+    mapping: Vec<(String, Vec<RoleId>)>,
+}
+
+impl RoleMap {
+    /// The roles a membership of `membership_type` should have, besides the member role.
+    pub fn roles_for(&self, membership_type: Option<&str>) -> Vec<RoleId> {
+        let membership_type = match membership_type {
+            Some(membership_type) => membership_type.trim().to_lowercase(),
+            None => return vec![],
+        };
+        self.mapping
+            .iter()
+            .find(|(t, _)| *t == membership_type)
+            .map(|(_, roles)| roles.clone())
+            .unwrap_or_default()
+    }
+
+    /// The roles `membership` should have through its membership type or tags, besides the member
+    /// role.
+    pub fn roles_for_membership(&self, membership: &Membership) -> Vec<RoleId> {
+        let mut roles = self.roles_for(membership.membership_type.as_deref());
+        for tag in &membership.tags {
+            for role in self.roles_for(Some(tag)) {
+                if !roles.contains(&role) {
+                    roles.push(role);
+                }
+            }
+        }
+        roles
+    }
+
+    /// Every role the mapping hands out.
+    pub fn all(&self) -> Vec<RoleId> {
+        let mut seen = HashSet::new();
+        self.mapping
+            .iter()
+            .flat_map(|(_, roles)| roles)
+            .filter(|r| seen.insert(**r))
+            .copied()
+            .collect()
+    }
+
+    /// The mapped roles to add to and remove from a member holding `held` whose membership should
+    /// give them `wanted`.
+    pub fn changes(&self, held: &[RoleId], wanted: &[RoleId]) -> (Vec<RoleId>, Vec<RoleId>) {
+        let add = wanted
+            .iter()
+            .filter(|r| !held.contains(r))
+            .copied()
+            .collect();
+        let remove = self
+            .all()
+            .into_iter()
+            .filter(|r| held.contains(r) && !wanted.contains(r))
+            .collect();
+        (add, remove)
+    }
+}
+
+/// Brings the type roles of every registered member in line with their current membership type,
+/// in every server using the memberships of `config`'s scope.
+pub async fn sync(config: &Config) -> Result<()> {
+    let http = config.get_http();
+    for guild in guild_members::fetch_guilds(&http).await? {
+        let guild_config = config.for_guild(*guild.id.as_u64())?;
+        if guild_config.scope != config.scope {
+            continue;
+        }
+        if let Err(e) = sync_guild(&guild_config, &http, guild.id).await {
+            log::error!("Failed to sync type roles in {}: {}", guild.name, e);
+        }
+    }
+    Ok(())
+}
+
+async fn sync_guild(config: &Config, http: &Http, guild_id: GuildId) -> Result<()> {
+    let roles = guild_id.roles(http).await?;
+    let member_role = config.member_role(&roles)?;
+    let role_map = config.type_role_map(&roles);
+    // Pin roles configured since startup too, missing ones were already warned about
+    pin(
+        &config.get_sqlite_conn()?,
+        config,
+        *guild_id.as_u64(),
+        &roles,
+    )?;
+    // Lapsed memberships keep their roles until they're pruned
+    let memberships: HashMap<u64, Membership> =
+        Membership::get_all(&config.get_sqlite_conn()?, config.scope, config.guild_id)?
+            .into_iter()
+            .filter(|m| !m.should_drop)
+            .filter_map(|m| Some((m.discord_id?, m)))
+            .collect();
+
+    for mut member in guild_members::fetch_all(http, guild_id).await? {
+        let membership = match memberships.get(member.user.id.as_u64()) {
+            Some(membership) if member.roles.contains(&member_role) => membership,
+            _ => continue,
+        };
+        let wanted = role_map.roles_for_membership(membership);
+        let (add, remove) = role_map.changes(&member.roles, &wanted);
+        // One member we can't change shouldn't hold up everyone else
+        if !add.is_empty() {
+            log::info!("Adding type roles to {}", member.user.name);
+            if let Err(e) = member.add_roles(http, &add).await {
+                log::error!("Failed to add type roles to {}: {}", member.user.name, e);
+            }
+        }
+        if !remove.is_empty() {
+            log::info!("Removing type roles from {}", member.user.name);
+            if let Err(e) = member.remove_roles(http, &remove).await {
+                log::error!(
+                    "Failed to remove type roles from {}: {}",
+                    member.user.name,
+                    e
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::config::Config;
+    use crate::membership::Membership;
+    use crate::migrations::migrate;
+    use crate::type_roles::{pin, pinned, TypeRoles};
+    use poise::serenity_prelude::{Role, RoleId};
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    pub(crate) fn role(id: u64, name: &str) -> (RoleId, Role) {
+        let role: Role = serde_json::from_value(json!({
+            "id": id.to_string(),
+            "guild_id": "1",
+            "color": 0,
+            "hoist": false,
+            "managed": false,
+            "mentionable": false,
+            "name": name,
+            "permissions": "0",
+            "position": 1,
+        }))
+        .unwrap();
+        (role.id, role)
+    }
+
+    #[test]
+    fn parse_and_resolve() {
+        let type_roles: TypeRoles = "Full Membership=Full; Alumni = Alumni, Friends ;"
+            .parse()
+            .unwrap();
+        assert!("Full Membership".parse::<TypeRoles>().is_err());
+        assert!("Full Membership=".parse::<TypeRoles>().is_err());
+        assert!("".parse::<TypeRoles>().unwrap().is_empty());
+
+        let roles: HashMap<RoleId, Role> = [role(1, "Full"), role(2, "Alumni"), role(3, "Friends")]
+            .into_iter()
+            .collect();
+        let role_map = type_roles.resolve(&roles, &HashMap::new());
+        assert_eq!(role_map.roles_for(Some("full membership")), vec![RoleId(1)]);
+        assert_eq!(
+            role_map.roles_for(Some("Alumni")),
+            vec![RoleId(2), RoleId(3)]
+        );
+        assert!(role_map.roles_for(Some("Associate")).is_empty());
+        assert!(role_map.roles_for(None).is_empty());
+        assert_eq!(role_map.all(), vec![RoleId(1), RoleId(2), RoleId(3)]);
+        let tagged = Membership {
+            membership_type: Some("Full Membership".to_string()),
+            tags: vec!["alumni".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            role_map.roles_for_membership(&tagged),
+            vec![RoleId(1), RoleId(2), RoleId(3)]
+        );
+
+        let (add, remove) =
+            role_map.changes(&[RoleId(1), RoleId(3), RoleId(9)], &[RoleId(2), RoleId(3)]);
+        assert_eq!(add, vec![RoleId(2)]);
+        assert_eq!(remove, vec![RoleId(1)]);
+
+        let missing: TypeRoles = "Life=Life Member,Full".parse().unwrap();
+        assert_eq!(
+            missing
+                .resolve(&roles, &HashMap::new())
+                .roles_for(Some("life")),
+            vec![RoleId(1)]
+        );
+
+        let mut ambiguous = roles.clone();
+        ambiguous.extend([role(4, "Full")]);
+        let role_map = type_roles.resolve(&ambiguous, &HashMap::new());
+        assert!(role_map.roles_for(Some("full membership")).is_empty());
+        assert_eq!(role_map.all(), vec![RoleId(2), RoleId(3)]);
+    }
+
+    #[test]
+    fn pinned_roles_survive_renames() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config {
+            membership_type_roles: "Alumni=Alumni; Full=Full".parse().unwrap(),
+            ..Config::test(dir.path())
+        };
+        let mut conn = config.get_sqlite_conn().unwrap();
+        migrate(&mut conn).unwrap();
+
+        let mut roles: HashMap<RoleId, Role> =
+            [role(1, "Full"), role(2, "Alumni")].into_iter().collect();
+        assert!(pin(&conn, &config, 42, &roles).unwrap().is_empty());
+        config.type_role_ids = pinned(&conn, 42).unwrap();
+        assert_eq!(config.type_role_ids.get("Alumni"), Some(&2));
+        assert!(pinned(&conn, 43).unwrap().is_empty());
+
+        roles.insert(RoleId(2), role(2, "Old Members").1);
+        roles.remove(&RoleId(1));
+        let role_map = config.type_role_map(&roles);
+        assert_eq!(role_map.roles_for(Some("alumni")), vec![RoleId(2)]);
+        assert!(role_map.roles_for(Some("full")).is_empty());
+        assert_eq!(pin(&conn, &config, 42, &roles).unwrap().len(), 1);
+
+        // A deleted role that's been recreated is found by its name again
+        roles.insert(RoleId(3), role(3, "Full").1);
+        assert_eq!(
+            config.type_role_map(&roles).roles_for(Some("full")),
+            vec![RoleId(3)]
+        );
+        assert!(pin(&conn, &config, 42, &roles).unwrap().is_empty());
+        assert_eq!(pinned(&conn, 42).unwrap().get("Full"), Some(&3));
+    }
+}